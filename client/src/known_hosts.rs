@@ -0,0 +1,67 @@
+use std::{
+	collections::HashMap,
+	fs::{self, OpenOptions},
+	io::{self, ErrorKind, Write},
+	path::{Path, PathBuf},
+};
+
+// relative to the working directory, one "address fingerprint" pair per line
+pub const KNOWN_HOSTS: &str = "known_hosts";
+
+pub enum HostStatus {
+	Trusted,
+	Unknown,
+	Changed(String), // the fingerprint we trusted before
+}
+
+/*
+Trust on first use, the first key a server presents is remembered (once the user accepts it)
+and every later connection to the same address has to present that exact key again
+*/
+pub struct KnownHosts {
+	path: PathBuf,
+	hosts: HashMap<String, String>, // address, fingerprint
+}
+
+impl KnownHosts {
+	pub fn open(path: &Path) -> io::Result<Self> {
+		let content = match fs::read_to_string(path) {
+			Ok(content) => content,
+			Err(e) if e.kind() == ErrorKind::NotFound => String::new(),
+			Err(e) => return Err(e),
+		};
+
+		let hosts = content
+			.lines()
+			.filter(|line| !line.trim().is_empty() && !line.starts_with('#'))
+			.filter_map(|line| line.split_once(' '))
+			.map(|(address, fingerprint)| (address.to_string(), fingerprint.trim().to_string()))
+			.collect();
+
+		Ok(Self {
+			path: path.to_path_buf(),
+			hosts,
+		})
+	}
+
+	pub fn check(&self, address: &str, fingerprint: &str) -> HostStatus {
+		match self.hosts.get(address) {
+			Some(known) if known == fingerprint => HostStatus::Trusted,
+			Some(known) => HostStatus::Changed(known.clone()),
+			None => HostStatus::Unknown,
+		}
+	}
+
+	// only ever used for addresses we have not seen, a changed key has to be removed from the file by hand
+	pub fn trust(&mut self, address: &str, fingerprint: &str) -> io::Result<()> {
+		let mut file = OpenOptions::new()
+			.create(true)
+			.append(true)
+			.open(&self.path)?;
+		writeln!(file, "{} {}", address, fingerprint)?;
+
+		self.hosts
+			.insert(address.to_string(), fingerprint.to_string());
+		Ok(())
+	}
+}
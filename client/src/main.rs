@@ -1,3 +1,4 @@
+mod known_hosts;
 mod socket;
 mod tcp_client;
 mod window;
@@ -1,63 +1,162 @@
-use crate::tcp_client::{server::listen_server, Event, OuterClient};
-use lib::{hash, hex, hex_hash};
+use crate::{
+	known_hosts::{HostStatus, KnownHosts},
+	tcp_client::{
+		server::{listen_server, Heartbeat},
+		Event, OuterClient,
+	},
+	window::{WindowEvent, WindowSender},
+};
+use lib::{
+	handshake::{derive_session_keys, transcript_hash, verify_transcript},
+	io,
+};
 use rand_core::OsRng;
-use rsa::{PaddingScheme, PublicKey, RsaPublicKey};
+use rsa::{pkcs8::FromPublicKey, PaddingScheme, PublicKey, PublicKeyParts, RsaPublicKey};
 use std::{
 	error::Error,
-	io::{IoSlice, Read, Write},
 	net::{AddrParseError, SocketAddr},
+	time::Duration,
+};
+use tokio::{
+	io::{AsyncReadExt, AsyncWriteExt},
+	net::{tcp::OwnedReadHalf, TcpStream},
+	sync::{oneshot, watch},
+	time::timeout,
 };
-use tokio::net::{tcp::OwnedReadHalf, TcpStream};
 use x25519_dalek::{EphemeralSecret, PublicKey as DHPublicKey};
 
 pub fn to_socket_addr(address: String) -> Result<std::net::SocketAddr, AddrParseError> {
 	address.parse::<SocketAddr>()
 }
 
+// how long the server gets to accept the connection & to answer each step of the handshake
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+// waits between reconnect attempts double up to this
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
+pub fn reconnect_delay(attempt: u32) -> Duration {
+	Duration::from_secs(1 << attempt.min(6)).min(MAX_RECONNECT_DELAY)
+}
+
+// the server's key was rejected or changed, trying again won't fix that
+#[derive(Debug)]
+pub struct UntrustedServer(String);
+
+impl std::fmt::Display for UntrustedServer {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "{}", self.0)
+	}
+}
+
+impl Error for UntrustedServer {}
+
+// read_exact which gives up on a server that stopped talking
+async fn read_timeout(
+	stream: &mut TcpStream,
+	buff: &mut [u8],
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+	match timeout(READ_TIMEOUT, stream.read_exact(buff)).await {
+		Ok(result) => {
+			result?;
+			Ok(())
+		}
+		Err(_) => Err("the server took too long to answer the handshake".into()),
+	}
+}
+
 pub struct Socket {
 	socket_addr: SocketAddr,
-	public_key: RsaPublicKey,
+	known_hosts: KnownHosts,
+	window_sender: WindowSender, // to ask the user about keys we have not seen yet
 	outer: Option<OuterClient>,
 	read: Option<OwnedReadHalf>,
+	heartbeat: watch::Receiver<Heartbeat>, // what the server asked for in its hello reply
 }
 
 impl Socket {
-	pub fn new(address: String, public_key: RsaPublicKey) -> Result<Self, Box<dyn Error>> {
+	pub fn new(
+		address: String,
+		known_hosts: KnownHosts,
+		window_sender: WindowSender,
+		heartbeat: watch::Receiver<Heartbeat>,
+	) -> Result<Self, Box<dyn Error>> {
 		let socket_addr = to_socket_addr(address)?;
 
 		Ok(Self {
 			socket_addr,
-			public_key,
+			known_hosts,
+			window_sender,
 			outer: None,
 			read: None,
+			heartbeat,
 		})
 	}
 
+	// reads the public key the server presents & checks it against our known_hosts
+	async fn verify_server_key(
+		&mut self,
+		stream: &mut TcpStream,
+	) -> Result<RsaPublicKey, Box<dyn std::error::Error + Send + Sync>> {
+		let mut size_buff = [0u8; 2];
+		read_timeout(stream, &mut size_buff).await?;
+		let mut key_buff = vec![0u8; u16::from_be_bytes(size_buff) as usize];
+		read_timeout(stream, &mut key_buff).await?;
+
+		let public_key = RsaPublicKey::from_public_key_der(&key_buff)?;
+		let fingerprint = io::fingerprint(&public_key).map_err(|e| e.to_string())?;
+		let address = self.socket_addr.to_string();
+
+		match self.known_hosts.check(&address, &fingerprint) {
+			HostStatus::Trusted => {}
+			HostStatus::Changed(known) => {
+				return Err(Box::new(UntrustedServer(format!(
+					"THE KEY OF {} HAS CHANGED! expected {} but got {}, someone could be intercepting the connection. \
+					if the server really did change its key remove it from the known_hosts file",
+					address, known, fingerprint
+				))));
+			}
+			HostStatus::Unknown => {
+				let (reply, accepted) = oneshot::channel();
+				self.window_sender.send(WindowEvent::TrustServer(
+					address.clone(),
+					fingerprint.clone(),
+					reply,
+				))?;
+
+				if !accepted.await.unwrap_or(false) {
+					return Err(Box::new(UntrustedServer(format!(
+						"the key of {} was not accepted",
+						address
+					))));
+				}
+				self.known_hosts.trust(&address, &fingerprint)?;
+			}
+		}
+
+		Ok(public_key)
+	}
+
 	pub async fn initalize(
 		&mut self,
 		outer: OuterClient,
 	) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-		// tokio's TcpStream does not work here as various things do not implement Send + Sync when awaiting for certain closures I guess?
-		// needed at key exchange, would error when awaiting
-		let mut std_stream = std::net::TcpStream::connect(self.socket_addr)?;
-		std_stream.set_nonblocking(true)?;
-
-		println!(
-			"IRC chat client listening on {}",
-			std_stream.local_addr().unwrap().to_string()
-		);
-
-		let me = hex_hash(
-			std_stream
-				.local_addr()
-				.expect("unable to get my address")
-				.to_string()
-				.as_bytes(),
-		);
+		let mut stream = match timeout(CONNECT_TIMEOUT, TcpStream::connect(self.socket_addr)).await
+		{
+			Ok(stream) => stream?,
+			Err(_) => return Err(format!("timed out connecting to {}", self.socket_addr).into()),
+		};
+
+		println!("IRC chat client listening on {}", stream.local_addr()?);
+
+		// not under a timeout as a first time connection waits on the user to accept the key
+		let public_key = self.verify_server_key(&mut stream).await?;
 
 		// key exchange
-		let (shared_secret, receipent) = {
-			let mut rng = rand2::thread_rng();
+		let (keys, receipent, me) = {
+			// thread_rng can't be held across an await, OsRng can
+			let mut rng = rand2::rngs::OsRng;
 
 			// create our private key & encrypt using the server's public key
 			let secret = EphemeralSecret::new(OsRng);
@@ -75,7 +174,7 @@ impl Socket {
 				and the server and forwards the data
 
 			solution:
-				1. the server will have a pre established public key already given to the client
+				1. the server will have a pre established public key already given to the client (pinned on first use)
 				2. the client will connect to the server and send their DH public key encrypted via the server's public key
 				3. the server will decrypt, and send back it's DH public key, thus a shared secret is acquired
 
@@ -84,75 +183,52 @@ impl Socket {
 
 			theoretically the same process (but weaker) as the SSL handshake without the CA certificate verification to get the public
 			key as the user gives the public keym, right?
+
+			that alone still lets a MITM hand us their own DH public key in place of the server's, so the server also signs
+			a hash of everything exchanged (look at lib/src/handshake.rs) which we check against the pinned key
 			*/
 
 			let padding = PaddingScheme::new_pkcs1v15_encrypt();
-			let public_encrypted = self
-				.public_key
-				.encrypt(&mut rng, padding, &public_bytes[..])?;
-			// std_stream.try_write(&public_encrypted)?;
-			std_stream.write_vectored(&[IoSlice::new(&public_encrypted)])?;
-
-			// send the client id along
-			std_stream.write(me.as_bytes())?;
+			let public_encrypted = public_key.encrypt(&mut rng, padding, &public_bytes[..])?;
+			stream.write_all(&public_encrypted).await?;
 
 			// now that we have sent our public key encrypted using the dedicated server's public key
-			// we wait for a response for their DHE public key
-			let mut buff = [0u8; 32];
-			let mut got_key_buf = false;
+			// we wait for a response of their DHE public key, their hashed id, the id they gave us
+			// & the signature over all of it
+			let mut key_buff = [0u8; 32];
+			let mut id_buff = [0u8; 64];
+			let mut my_id_buff = [0u8; 64];
+			let mut signature = vec![0u8; public_key.size()];
 			println!("getting key...");
-			while !got_key_buf {
-				match std_stream.peek(&mut [0u8; 32]) {
-					Ok(_) => {
-						let _ = std_stream.read_exact(&mut buff);
-						got_key_buf = true;
-					}
-					Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-						println!("blocking... retrying");
-					}
-					Err(_) => panic!("weird error?"),
-				};
-
-				std::thread::sleep(std::time::Duration::from_millis(250));
+			read_timeout(&mut stream, &mut key_buff).await?;
+			read_timeout(&mut stream, &mut id_buff).await?;
+			read_timeout(&mut stream, &mut my_id_buff).await?;
+			read_timeout(&mut stream, &mut signature).await?;
+
+			let transcript = transcript_hash(public_bytes, &my_id_buff, &key_buff, &id_buff);
+			if verify_transcript(&public_key, &transcript, &signature).is_err() {
+				return Err(
+					"the server's handshake signature does not match its key, aborting".into(),
+				);
 			}
 			println!("got key!");
-			let their_public = DHPublicKey::from(buff);
-
-			// same as above except for their hashed id
-			let mut buff = [0u8; 64];
-			let mut got_id_buf = false;
-			println!("getting id...");
-			while !got_id_buf {
-				match std_stream.peek(&mut [0u8; 64]) {
-					Ok(_) => {
-						let _ = std_stream.read_exact(&mut buff);
-						got_id_buf = true;
-					}
-					Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
-					Err(_) => panic!("weird error?"),
-				};
-
-				std::thread::sleep(std::time::Duration::from_millis(250));
-			}
-			println!("got id!");
-			let id = String::from_utf8(buff.to_vec())?;
 
-			// should zeroize our secret
-			(hash(secret.diffie_hellman(&their_public).as_bytes()), id)
+			let their_public = DHPublicKey::from(key_buff);
+			let id = String::from_utf8(id_buff.to_vec())?;
+			let me = String::from_utf8(my_id_buff.to_vec())?;
+
+			// the ephemeral secret & the raw DH output are wiped as they are dropped
+			let keys =
+				derive_session_keys(secret.diffie_hellman(&their_public).as_bytes(), &transcript);
+			(keys, id, me)
 		};
 
-		let stream = TcpStream::from_std(std_stream)?;
 		let (read, write) = stream.into_split();
 		outer.send(Event::SetWriter(write))?;
 
-		#[cfg(debug_assertions)]
-		println!(
-			"the server's ({}) shared secret is: {}\nmine address is: {}",
-			receipent.clone(),
-			hex(&shared_secret),
-			me
-		);
-		outer.send(Event::SetSharedKey(receipent, shared_secret))?;
+		println!("connected with session {}", me.clone());
+		outer.send(Event::SetSessionKeys(receipent, me, keys))?;
+		outer.send(Event::Hello)?;
 
 		self.outer = Some(outer);
 		self.read = Some(read);
@@ -164,7 +240,7 @@ impl Socket {
 		let read = self.read.take().expect("Socket has not been initalized");
 
 		println!("listening to server...");
-		listen_server(read, outer).await?;
+		listen_server(read, outer, self.heartbeat.clone()).await?;
 		Ok(())
 	}
 }
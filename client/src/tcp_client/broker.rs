@@ -1,34 +1,55 @@
 use super::{Event, InnerClient, Receiver};
-use crate::tcp_client::feed::handle_feed;
-use lib::{
-	encoding::{Decoder, Instruction},
-	encryption::decrypt,
-};
+use crate::tcp_client::feed::{handle_feed, handle_peer_feed};
+use lib::encoding::Decoder;
 
 pub async fn broker(mut receiver: Receiver, mut inner_client: InnerClient) {
 	while let Some(event) = receiver.recv().await {
 		match event {
 			Event::SetWriter(writer) => inner_client.set_writer(writer),
-			Event::SetSharedKey(recepient, key) => {
-				// currently the only recepient is the server
-				if key.len() != 32 {
-					println!("invalid key given: {:?} size: {:?}", key, key.len());
-					return;
-				}
-				inner_client.set_key(recepient, key);
-			}
-			Event::Instantiate(username) => {
-				// sort of a hello world
-				inner_client.send_instructions_to_all(vec![Instruction::Instantiate(username)]);
+			Event::SetSessionKeys(recepient, me, keys) => {
+				// the handshake only ever gives us the server's keys, peers are keyed in direct.rs
+				inner_client.set_server(recepient, me, keys)
 			}
+			Event::Hello => inner_client.send_hello(),
+			Event::Ping => inner_client.ping(),
+			Event::Instantiate(username) => inner_client.login(username),
 			Event::ReadFeed(sender_id, buf) => {
-				if let Some(secret) = inner_client.get_key(&sender_id) {
-					let data = decrypt(secret, buf);
-					let feed = Decoder::from_bytes(data);
-					handle_feed(&mut inner_client, feed.feed);
+				if let Some(session) = inner_client.get_session(&sender_id) {
+					// forged, replayed or reordered frames never make it to the feed
+					let data = match session.open(&buf) {
+						Ok(data) => data,
+						Err(e) => {
+							eprintln!("dropping frame from {sender_id}: {e}");
+							continue;
+						}
+					};
+					let feed = match Decoder::from_bytes(data) {
+						Ok(feed) => feed.feed,
+						Err(e) => {
+							eprintln!("skipping invalid feed from {sender_id}: {e}");
+							continue;
+						}
+					};
+
+					if inner_client.is_server(&sender_id) {
+						handle_feed(&mut inner_client, feed);
+					} else {
+						handle_peer_feed(&mut inner_client, &sender_id, feed);
+					}
 				}
 			}
 			Event::SendMessage(content) => inner_client.send_message(content),
+			Event::ListUsers => inner_client.list_users(),
+			Event::ChangeNick(username) => inner_client.change_nick(username),
+			Event::FetchHistory(before) => inner_client.fetch_history(before),
+			Event::JoinChannel(channel) => inner_client.join_channel(channel),
+			Event::PartChannel(channel) => inner_client.part_channel(channel),
+			Event::SendChannelMessage(channel, content) => {
+				inner_client.send_channel_message(channel, content)
+			}
+			Event::SendDirectMessage(username, content) => {
+				inner_client.send_direct_message(username, content)
+			}
 		}
 	}
 }
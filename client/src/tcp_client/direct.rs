@@ -0,0 +1,197 @@
+use super::InnerClient;
+use crate::window::WindowEvent;
+use lib::{encoding::Instruction, handshake::derive_session_keys, protocol::same_username};
+use rand_core::OsRng;
+use x25519_dalek::{EphemeralSecret, PublicKey};
+
+/*
+End to end encrypted direct messages
+
+	1. we generate an ephemeral key pair and send the public half to the server, addressed to a username
+	2. the server forwards it (with our id) to that user, who answers with their own public half
+	3. both sides now have the shared secret, the server only ever saw the public halves
+
+from then on frames are addressed to the peer's id & encrypted with the pairwise secret,
+the server relays them without being able to read them
+
+when both sides offer at once the lower id keeps its offer, the other side drops its own & answers
+
+problems:
+	the server could still swap the public halves (MITM) as there is nothing to verify them against yet
+*/
+pub struct PendingExchange {
+	secret: EphemeralSecret,
+	public: PublicKey,   // what we offered, part of the key derivation salt
+	queued: Vec<String>, // messages typed before the exchange finished
+}
+
+fn to_public_key(public_key: &[u8]) -> Option<PublicKey> {
+	let bytes: [u8; 32] = public_key.try_into().ok()?;
+	Some(PublicKey::from(bytes))
+}
+
+impl InnerClient {
+	pub fn peer_username(&self, id: &str) -> Option<&str> {
+		self.peers.get(id).map(|username| username.as_str())
+	}
+
+	fn find_peer(&self, username: &str) -> Option<String> {
+		self.peers
+			.iter()
+			.find(|(id, peer)| {
+				same_username(peer, username) && self.sessions.contains_key(id.as_str())
+			})
+			.map(|(id, _)| id.clone())
+	}
+
+	pub fn send_direct_message(&mut self, username: String, content: String) {
+		let _ = self.window_sender.send(WindowEvent::DisplayMessage(
+			format!("(dm to {username})"),
+			content.clone(),
+		));
+
+		if let Some(peer_id) = self.find_peer(&username) {
+			return self.send_instructions_to(&peer_id, vec![Instruction::DirectMessage(content)]);
+		}
+
+		// usernames are unique regardless of case, so is what we key exchanges by
+		let key = username.to_lowercase();
+		if let Some(pending) = self.pending_direct.get_mut(&key) {
+			return pending.queued.push(content);
+		}
+
+		let secret = EphemeralSecret::new(OsRng);
+		let public = PublicKey::from(&secret);
+		self.pending_direct.insert(
+			key,
+			PendingExchange {
+				secret,
+				public,
+				queued: vec![content],
+			},
+		);
+
+		// the server fills in our id when forwarding
+		self.send_instructions_to_server(vec![Instruction::DirectKeyOffer(
+			String::new(),
+			username,
+			public.as_bytes().to_vec(),
+		)]);
+	}
+
+	// the server couldn't find who we offered to, whatever we queued for them is dropped
+	pub fn direct_offer_failed(&mut self, username: String) {
+		self.pending_direct.remove(&username.to_lowercase());
+		let _ = self
+			.window_sender
+			.send(WindowEvent::Error(format!("{username} is not online")));
+	}
+
+	// their session went with their connection, the next /dm starts a new exchange
+	pub fn peer_left(&mut self, username: &str) {
+		let gone: Vec<String> = self
+			.peers
+			.iter()
+			.filter(|(_, peer)| same_username(peer, username))
+			.map(|(id, _)| id.clone())
+			.collect();
+		for id in gone {
+			self.peers.remove(&id);
+			self.sessions.remove(&id);
+		}
+		self.pending_direct.remove(&username.to_lowercase());
+	}
+
+	// the server had nobody to relay to, we missed (or raced) their UserLeft
+	pub fn peer_gone(&mut self, id: &str) {
+		self.sessions.remove(id);
+		if let Some(username) = self.peers.remove(id) {
+			let _ = self.window_sender.send(WindowEvent::Error(format!(
+				"{username} is no longer online"
+			)));
+		}
+	}
+
+	pub fn accept_key_offer(&mut self, peer_id: String, username: String, public_key: Vec<u8>) {
+		let their_public = match to_public_key(&public_key) {
+			Some(public) => public,
+			None => return eprintln!("invalid direct key offer from {username}"),
+		};
+
+		// we offered to them as well, only one of the two exchanges can win
+		let mut queued = vec![];
+		let key = username.to_lowercase();
+		if self.pending_direct.contains_key(&key) {
+			if self.id.as_deref().is_some_and(|me| me < peer_id.as_str()) {
+				// they answer ours instead
+				return;
+			}
+			if let Some(pending) = self.pending_direct.remove(&key) {
+				queued = pending.queued;
+			}
+		}
+
+		let secret = EphemeralSecret::new(OsRng);
+		let public = PublicKey::from(&secret);
+
+		// they offered, so they take the client's side of the keys
+		let salt = [their_public.as_bytes().as_slice(), public.as_bytes()].concat();
+		let keys = derive_session_keys(secret.diffie_hellman(&their_public).as_bytes(), &salt);
+		self.set_keys(
+			peer_id.clone(),
+			keys.server_to_client,
+			keys.client_to_server,
+		);
+		self.peers.insert(peer_id.clone(), username);
+		self.send_instructions_to_server(vec![Instruction::DirectKeyAnswer(
+			peer_id.clone(),
+			String::new(),
+			public.as_bytes().to_vec(),
+		)]);
+
+		// whatever we typed while our own offer was out goes over their exchange
+		if !queued.is_empty() {
+			let feed = queued.into_iter().map(Instruction::DirectMessage).collect();
+			self.send_instructions_to(&peer_id, feed);
+		}
+	}
+
+	pub fn complete_key_exchange(
+		&mut self,
+		peer_id: String,
+		username: String,
+		public_key: Vec<u8>,
+	) {
+		let pending = match self.pending_direct.remove(&username.to_lowercase()) {
+			Some(pending) => pending,
+			None => return eprintln!("unexpected direct key answer from {username}"),
+		};
+		let their_public = match to_public_key(&public_key) {
+			Some(public) => public,
+			None => return eprintln!("invalid direct key answer from {username}"),
+		};
+
+		let salt = [
+			pending.public.as_bytes().as_slice(),
+			their_public.as_bytes(),
+		]
+		.concat();
+		let keys = derive_session_keys(
+			pending.secret.diffie_hellman(&their_public).as_bytes(),
+			&salt,
+		);
+		self.set_keys(
+			peer_id.clone(),
+			keys.client_to_server,
+			keys.server_to_client,
+		);
+		self.peers.insert(peer_id.clone(), username);
+
+		let feed = pending
+			.queued
+			.into_iter()
+			.map(Instruction::DirectMessage)
+			.collect();
+		self.send_instructions_to(&peer_id, feed);
+	}
+}
@@ -1,17 +1,119 @@
 use crate::window::WindowEvent;
 
 use super::InnerClient;
-use lib::encoding::Instruction::{self, *};
+use lib::{
+	encoding::{
+		ErrorCode,
+		Instruction::{self, *},
+	},
+	protocol::{now_millis, Capabilities},
+};
 
 pub fn handle_feed(client: &mut InnerClient, feed: Vec<Instruction>) {
 	for instr in feed {
 		match instr {
+			Rekey(generation) => {
+				if let Some(server_id) = client.server_id.clone() {
+					client.rekey_from(&server_id, generation)
+				}
+			}
+			Ping(sent) => client.send_instructions_to_server(vec![Pong(sent)]),
+			Pong(sent) => {
+				// round trip, only our own clock is involved
+				let latency = now_millis().saturating_sub(sent);
+				let _ = client.window_sender.send(WindowEvent::Latency(latency));
+			}
+			Hello(version, capabilities) => {
+				let capabilities = Capabilities::from_bits(capabilities);
+				println!(
+					"negotiated protocol version {version} with capabilities {capabilities:?}"
+				);
+				client.set_protocol(version, capabilities);
+			}
+			Heartbeat { interval, missed } => client.set_heartbeat(interval, missed),
+			ResumeToken(token) => client.set_resume_token(token),
+			ServerShutdown {
+				reason,
+				reconnect_after,
+			} => client.server_shutdown(reason, reconnect_after),
+			Error(ErrorCode::InvalidResume, reason) => {
+				println!("unable to resume: {reason}");
+				client.resume_failed();
+			}
+			Error(code, reason) => {
+				eprintln!("server error ({code:?}): {reason}");
+				let _ = client.window_sender.send(WindowEvent::Error(reason));
+			}
 			ReceiveMessage(username, content) => {
 				println!("receiving message: {username}: {content}");
 				let _ = client
 					.window_sender
 					.send(WindowEvent::DisplayMessage(username, content));
 			}
+			ChannelMessage(channel, username, content) => {
+				let _ = client.window_sender.send(WindowEvent::DisplayMessage(
+					format!("{channel} {username}"),
+					content,
+				));
+			}
+			ChannelJoined(channel, username) => {
+				let _ = client
+					.window_sender
+					.send(WindowEvent::Notice(format!("{username} joined {channel}")));
+			}
+			ChannelParted(channel, username) => {
+				let _ = client
+					.window_sender
+					.send(WindowEvent::Notice(format!("{username} left {channel}")));
+			}
+			UserList(usernames) => {
+				let _ = client.window_sender.send(WindowEvent::UserList(usernames));
+			}
+			UserJoined(username) => {
+				let _ = client.window_sender.send(WindowEvent::UserJoined(username));
+			}
+			UserLeft(username) => {
+				client.peer_left(&username);
+				let _ = client.window_sender.send(WindowEvent::UserLeft(username));
+			}
+			History { entries } => {
+				let _ = client.window_sender.send(WindowEvent::History(entries));
+			}
+			NickChanged(old_username, new_username) => {
+				client.rename_peer(&old_username, &new_username);
+				let _ = client
+					.window_sender
+					.send(WindowEvent::UserRenamed(old_username, new_username));
+			}
+			DirectKeyOffer(peer_id, username, public_key) => {
+				client.accept_key_offer(peer_id, username, public_key)
+			}
+			DirectKeyAnswer(peer_id, username, public_key) => {
+				client.complete_key_exchange(peer_id, username, public_key)
+			}
+			DirectKeyRejected { username } => client.direct_offer_failed(username),
+			PeerGone { id } => client.peer_gone(&id),
+			_ => {}
+		}
+	}
+}
+
+// feeds coming straight from another client, only direct messages are accepted from them
+pub fn handle_peer_feed(client: &mut InnerClient, sender_id: &str, feed: Vec<Instruction>) {
+	let username = match client.peer_username(sender_id) {
+		Some(username) => username.to_string(),
+		None => return,
+	};
+
+	for instr in feed {
+		match instr {
+			Rekey(generation) => client.rekey_from(sender_id, generation),
+			DirectMessage(content) => {
+				let _ = client.window_sender.send(WindowEvent::DisplayMessage(
+					format!("(dm) {username}"),
+					content,
+				));
+			}
 			_ => {}
 		}
 	}
@@ -1,27 +1,59 @@
-use crate::window::WindowSender;
+use crate::window::{WindowEvent, WindowSender};
 
-use self::broker::broker;
+use self::{
+	broker::broker,
+	server::{write_server, Heartbeat},
+};
 use lib::{
+	codec::{encode_frame, Frame, FrameError, DEFAULT_MAX_FRAME_SIZE},
 	encoding::{Encoder, Instruction},
-	encryption,
+	encryption::{Sealer, SecretKey, Session},
+	handshake::SessionKeys,
+	protocol::{now_millis, same_username, Capabilities, PROTOCOL_VERSION},
+};
+use std::{collections::HashMap, time::Duration};
+use tokio::{
+	net::tcp::OwnedWriteHalf,
+	sync::{
+		mpsc::{self, error::TrySendError},
+		watch,
+	},
 };
-use std::collections::HashMap;
-use tokio::{net::tcp::OwnedWriteHalf, sync::mpsc};
 mod broker;
+mod direct;
 mod feed;
 pub mod server;
 
 #[derive(Debug)]
 pub enum Event {
-	SetWriter(OwnedWriteHalf),     // the writer
-	SetSharedKey(String, Vec<u8>), // recepient, key
-	Instantiate(String),           // username
-	ReadFeed(String, Vec<u8>),     // sender id, buf
-	SendMessage(String),           // content
+	SetWriter(OwnedWriteHalf),                   // the writer
+	SetSessionKeys(String, String, SessionKeys), // server id, our session id, keys
+	Hello,                                       // advertise our protocol version & capabilities
+	Ping,                                        // heartbeat, look at server.rs -> listen_server
+	Instantiate(String),                         // username, resumes the last session if there was one
+	ReadFeed(String, Vec<u8>),                   // sender id, buf
+	SendMessage(String),                         // content
+	JoinChannel(String),                         // channel
+	PartChannel(String),                         // channel
+	SendChannelMessage(String, String),          // channel, content
+	SendDirectMessage(String, String),           // username, content
+	ListUsers,
+	ChangeNick(String), // new username
+	FetchHistory(u64),  // oldest message id we have
 }
 pub type Sender = mpsc::UnboundedSender<Event>;
 pub type Receiver = mpsc::UnboundedReceiver<Event>;
 
+// optional protocol features this client implements, see lib/src/protocol.rs
+pub const SUPPORTED_CAPABILITIES: Capabilities = Capabilities::CHANNELS;
+
+// how many older messages to ask for at a time
+const HISTORY_PAGE: u16 = 50;
+
+// payloads waiting for the writer task, past this whatever we try to send is dropped
+const WRITE_QUEUE: usize = 64;
+
+#[derive(Clone)]
 pub struct OuterClient(Sender);
 
 impl OuterClient {
@@ -42,64 +74,253 @@ impl OuterClient {
 }
 
 // payload is in:
-// size hint (64 bits) | recepient (512 bits) | encrypted buffer
-fn make_payload(recepient: &String, key: &[u8], buff: &[u8]) -> Vec<u8> {
-	let recepient = recepient.as_bytes().to_vec();
-	let encrypted_buf = encryption::encrypt(key, buff);
-	let len = encrypted_buf.len() as u64;
-	[len.to_be_bytes().to_vec(), recepient, encrypted_buf].concat()
+// size hint (64 bits) | recepient (512 bits) | encrypted buffer, look at lib/src/encryption.rs -> Session
+fn make_payload(
+	recepient: &str,
+	session: &mut Session,
+	buff: &[u8],
+) -> Result<Vec<u8>, FrameError> {
+	let encrypted_buf = session.seal(buff);
+	encode_frame(Frame::new(recepient.to_string(), encrypted_buf))
 }
 
 pub struct InnerClient {
-	keys: HashMap<String, Vec<u8>>,
-	writer: Option<OwnedWriteHalf>,
+	sessions: HashMap<String, Session>, // server or peer id -> encrypted session
+	id: Option<String>,                 // our session id, given by the server
+	server_id: Option<String>,
+	username: Option<String>, // what we asked for, or what the server renamed us to
+	resume_token: Option<String>, // to pick up the same session after a reconnect
+	reconnect_after: watch::Sender<Duration>, // how long the server asked us to wait before reconnecting
+	heartbeat: watch::Sender<Heartbeat>, // how often the server wants to hear from us, look at server.rs -> listen_server
+	peers: HashMap<String, String>,      // peer id -> username, for direct messages
+	pending_direct: HashMap<String, direct::PendingExchange>, // lowercased username -> key exchange in flight
+	writer: Option<mpsc::Sender<Vec<u8>>>, // to the writer task, look at server.rs -> write_server
 	window_sender: WindowSender,
+	protocol_version: Option<u16>, // set once the server answered our hello
+	capabilities: Capabilities,
 }
 impl InnerClient {
-	pub fn new(window_sender: WindowSender) -> Self {
+	pub fn new(
+		window_sender: WindowSender,
+		reconnect_after: watch::Sender<Duration>,
+		heartbeat: watch::Sender<Heartbeat>,
+	) -> Self {
 		Self {
-			keys: HashMap::new(),
+			sessions: HashMap::new(),
+			id: None,
+			server_id: None,
+			username: None,
+			resume_token: None,
+			reconnect_after,
+			heartbeat,
+			peers: HashMap::new(),
+			pending_direct: HashMap::new(),
 			writer: None,
 			window_sender,
+			protocol_version: None,
+			capabilities: Capabilities::empty(),
 		}
 	}
 
+	pub fn set_protocol(&mut self, version: u16, capabilities: Capabilities) {
+		self.protocol_version = Some(version);
+		self.capabilities = capabilities;
+	}
+
 	pub fn set_writer(&mut self, writer: OwnedWriteHalf) {
-		self.writer = Some(writer);
+		let (sender, receiver) = mpsc::channel(WRITE_QUEUE);
+		tokio::spawn(write_server(writer, receiver, self.window_sender.clone()));
+		self.writer = Some(sender);
 	}
 
-	pub fn set_key(&mut self, recepient: String, key: Vec<u8>) {
+	pub fn set_keys(&mut self, recepient: String, send_key: SecretKey, receive_key: SecretKey) {
 		if recepient.len() != 64 {
 			// look at lib/src/lib.rs -> hex_hash
 			return println!("invalid key");
 		}
 
-		self.keys.insert(recepient, key);
+		let me = match &self.id {
+			Some(me) => me.clone(),
+			None => return println!("no session id yet"),
+		};
+		let session = Session::new(send_key, receive_key, me, recepient.clone());
+		self.sessions.insert(recepient, session);
 	}
 
-	pub fn get_key(&self, id: &str) -> Option<&Vec<u8>> {
-		self.keys.get(id)
+	// a new connection, anything keyed to the last one (direct message sessions included) is gone
+	pub fn set_server(&mut self, id: String, me: String, keys: SessionKeys) {
+		self.sessions.clear();
+		self.peers.clear();
+		self.pending_direct.clear();
+		self.protocol_version = None;
+
+		self.server_id = Some(id.clone());
+		self.id = Some(me);
+		self.set_keys(id, keys.client_to_server, keys.server_to_client);
+	}
+
+	pub fn get_session(&mut self, id: &str) -> Option<&mut Session> {
+		self.sessions.get_mut(id)
 	}
 
-	pub fn relay_data_to_all(&mut self, buff: &[u8]) {
-		if let Some(write) = &self.writer {
-			for (recepient, key) in self.keys.iter() {
-				let payload = make_payload(recepient, key, buff);
+	// the other end moved its sending key forward, we follow along for reading
+	pub fn rekey_from(&mut self, id: &str, generation: u64) {
+		let result = match self.sessions.get_mut(id) {
+			Some(session) => session.rekey_receive(generation),
+			None => return,
+		};
 
-				match write.try_write(&payload) {
-					Ok(_) => {}
-					Err(e) => eprintln!("{}", e.to_string()),
-				};
-			}
+		if let Err(e) = result {
+			eprintln!("invalid rekey from {id}: {e}");
+			let _ = self
+				.window_sender
+				.send(WindowEvent::Error(format!("invalid rekey: {e}")));
 		}
 	}
 
-	pub fn send_instructions_to_all(&mut self, feed: Vec<Instruction>) {
+	pub fn is_server(&self, id: &str) -> bool {
+		self.server_id.as_deref() == Some(id)
+	}
+
+	pub fn relay_data_to(&mut self, recepient: &str, buff: &[u8]) {
+		let (write, session) = match (&self.writer, self.sessions.get_mut(recepient)) {
+			(Some(write), Some(session)) => (write, session),
+			_ => return eprintln!("no connection or key for {}", recepient),
+		};
+
+		if !Sealer::fits(buff.len(), DEFAULT_MAX_FRAME_SIZE) {
+			let _ = self.window_sender.send(WindowEvent::Error(format!(
+				"{} bytes is too much to send at once",
+				buff.len()
+			)));
+			return;
+		}
+		let permit = match write.try_reserve() {
+			Ok(permit) => permit,
+			Err(TrySendError::Full(_)) => {
+				let _ = self.window_sender.send(WindowEvent::Error(format!(
+					"the connection is not keeping up ({} payloads waiting), nothing was sent",
+					WRITE_QUEUE
+				)));
+				return;
+			}
+			Err(TrySendError::Closed(_)) => return eprintln!("the connection is closed"),
+		};
+
+		let payload = match make_payload(recepient, session, buff) {
+			Ok(payload) => payload,
+			Err(e) => return eprintln!("unable to make payload: {}", e),
+		};
+		permit.send(payload);
+	}
+
+	pub fn send_instructions_to(&mut self, recepient: &str, feed: Vec<Instruction>) {
 		let data = Encoder::from_feed(feed).writer.dump();
-		self.relay_data_to_all(&data)
+		self.relay_data_to(recepient, &data)
+	}
+
+	pub fn send_instructions_to_server(&mut self, feed: Vec<Instruction>) {
+		if let Some(server_id) = self.server_id.clone() {
+			self.send_instructions_to(&server_id, feed)
+		}
+	}
+
+	pub fn send_hello(&mut self) {
+		self.send_instructions_to_server(vec![Instruction::Hello(
+			PROTOCOL_VERSION,
+			SUPPORTED_CAPABILITIES.bits(),
+		)])
+	}
+
+	pub fn ping(&mut self) {
+		self.send_instructions_to_server(vec![Instruction::Ping(now_millis())])
+	}
+
+	pub fn login(&mut self, username: String) {
+		self.username = Some(username.clone());
+
+		let instruction = match self.resume_token.clone() {
+			Some(token) => Instruction::Resume(token),
+			None => Instruction::Instantiate(username),
+		};
+		self.send_instructions_to_server(vec![instruction])
+	}
+
+	pub fn set_resume_token(&mut self, token: String) {
+		self.resume_token = Some(token);
+	}
+
+	// the server forgot about us, so we start over with the username we had
+	pub fn resume_failed(&mut self) {
+		self.resume_token = None;
+		if let Some(username) = self.username.clone() {
+			// the lost session may still hold the username for a while, the server says how long if so
+			let _ = self.window_sender.send(WindowEvent::Notice(format!(
+				"unable to resume the last session, logging in as {username} again"
+			)));
+			self.login(username);
+		}
+	}
+
+	pub fn set_heartbeat(&mut self, interval: u64, missed: u64) {
+		self.heartbeat
+			.send_replace(Heartbeat::from_server(interval, missed));
+	}
+
+	// the server is going away, the reconnect loop (look at window/mod.rs -> create_tcp_client) waits as long as it asked
+	pub fn server_shutdown(&mut self, reason: String, reconnect_after: u64) {
+		self.reconnect_after
+			.send_replace(Duration::from_secs(reconnect_after));
+		let _ = self.window_sender.send(WindowEvent::Notice(format!(
+			"server shutting down: {reason}"
+		)));
 	}
 
 	pub fn send_message(&mut self, content: String) {
-		self.send_instructions_to_all(vec![Instruction::SendMessage(content)])
+		self.send_instructions_to_server(vec![Instruction::SendMessage(content)])
+	}
+
+	pub fn change_nick(&mut self, username: String) {
+		self.send_instructions_to_server(vec![Instruction::ChangeNick(username)])
+	}
+
+	// keeps direct message peers addressable by their new name
+	pub fn rename_peer(&mut self, old_username: &str, new_username: &str) {
+		if self.username.as_deref() == Some(old_username) {
+			self.username = Some(new_username.to_string());
+		}
+
+		self.peers
+			.values_mut()
+			.filter(|username| same_username(username, old_username))
+			.for_each(|username| *username = new_username.to_string());
+	}
+
+	pub fn fetch_history(&mut self, before: u64) {
+		self.send_instructions_to_server(vec![Instruction::FetchHistory {
+			before,
+			limit: HISTORY_PAGE,
+		}])
+	}
+
+	pub fn list_users(&mut self) {
+		self.send_instructions_to_server(vec![Instruction::ListUsers])
+	}
+
+	pub fn join_channel(&mut self, channel: String) {
+		self.send_instructions_to_server(vec![Instruction::JoinChannel(channel)])
+	}
+
+	pub fn part_channel(&mut self, channel: String) {
+		self.send_instructions_to_server(vec![Instruction::PartChannel(channel)])
+	}
+
+	pub fn send_channel_message(&mut self, channel: String, content: String) {
+		// the server fills in the author
+		self.send_instructions_to_server(vec![Instruction::ChannelMessage(
+			channel,
+			String::new(),
+			content,
+		)])
 	}
 }
@@ -1,20 +1,99 @@
 use super::{Event, OuterClient};
-use lib::stream::{self, StreamOperation};
-use tokio::net::tcp::OwnedReadHalf;
+use crate::window::{WindowEvent, WindowSender};
+use futures::StreamExt;
+use lib::{
+	codec::FrameCodec,
+	protocol::{MAX_HEARTBEAT_INTERVAL, MAX_MISSED_HEARTBEATS},
+};
+use std::time::Duration;
+use tokio::{
+	io::AsyncWriteExt,
+	net::tcp::{OwnedReadHalf, OwnedWriteHalf},
+	sync::{mpsc, watch},
+	time::{self, Instant},
+};
+use tokio_util::codec::FramedRead;
+
+// we ping the server this often (which also gives us the latency) & give up after this many go unanswered
+#[derive(Debug, Clone, Copy)]
+pub struct Heartbeat {
+	pub interval: Duration,
+	pub missed: u32,
+}
+
+impl Default for Heartbeat {
+	// until the server tells us its own
+	fn default() -> Self {
+		Self {
+			interval: Duration::from_secs(15),
+			missed: 3,
+		}
+	}
+}
+
+impl Heartbeat {
+	// what the server asked for, kept within bounds so the timers can't overflow
+	pub fn from_server(interval: u64, missed: u64) -> Self {
+		Self {
+			interval: Duration::from_secs(interval.clamp(1, MAX_HEARTBEAT_INTERVAL)),
+			missed: missed.clamp(1, MAX_MISSED_HEARTBEATS as u64) as u32,
+		}
+	}
+
+	fn timeout(&self) -> Duration {
+		self.interval * self.missed
+	}
+}
 
 pub async fn listen_server(
-	mut read: OwnedReadHalf,
+	read: OwnedReadHalf,
 	outer: OuterClient,
+	mut settings: watch::Receiver<Heartbeat>,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+	let mut frames = FramedRead::new(read, FrameCodec::default());
+	let mut current = *settings.borrow_and_update();
+	let mut heartbeat = time::interval(current.interval);
+	let mut last_seen = Instant::now();
+
 	loop {
-		let (sender_id, encrypted_buf) = match stream::read_stream(&mut read).await {
-			Ok((r, e)) => (r, e),
-			Err(StreamOperation::Continue) => continue,
-			Err(StreamOperation::Break) => break,
-		};
+		tokio::select! {
+			frame = frames.next() => {
+				let frame = match frame {
+					Some(frame) => frame?,
+					None => return Ok(()),
+				};
+
+				last_seen = Instant::now();
+				outer.send(Event::ReadFeed(frame.id, frame.data))?;
+			}
+			Ok(()) = settings.changed() => {
+				current = *settings.borrow_and_update();
+				heartbeat = time::interval(current.interval);
+			}
+			_ = heartbeat.tick() => {
+				// a half open connection never errors, it just goes quiet
+				if last_seen.elapsed() >= current.timeout() {
+					return Err("the server stopped answering".into());
+				}
+				outer.send(Event::Ping)?;
+			}
+		}
+	}
+}
 
-		outer.send(Event::ReadFeed(sender_id, encrypted_buf))?;
+// the only place writing to the server, payloads come in already sealed & framed
+pub async fn write_server(
+	mut write: OwnedWriteHalf,
+	mut payloads: mpsc::Receiver<Vec<u8>>,
+	window_sender: WindowSender,
+) {
+	while let Some(payload) = payloads.recv().await {
+		if let Err(e) = write.write_all(&payload).await {
+			eprintln!("error while writing to the server: {e}");
+			let _ = window_sender.send(WindowEvent::Error(format!("connection lost: {e}")));
+			return;
+		}
 	}
 
-	Ok(())
+	let _ = write.shutdown().await;
 }
@@ -0,0 +1,35 @@
+use crate::tcp_client::Event;
+
+/*
+Turns what was typed into the chat box into an event for the tcp client,
+anything starting with a '/' is a command, everything else is a plain message
+*/
+pub fn parse_input(input: &str) -> Result<Event, String> {
+	let input = input.trim();
+	if !input.starts_with('/') {
+		return Ok(Event::SendMessage(input.to_string()));
+	}
+
+	let mut parts = input.splitn(3, ' ');
+	let command = parts.next().unwrap_or_default();
+	let argument = parts.next().unwrap_or_default().to_string();
+	let rest = parts.next().unwrap_or_default().to_string();
+
+	match command {
+		"/users" => Ok(Event::ListUsers),
+		"/nick" if !argument.is_empty() => Ok(Event::ChangeNick(argument)),
+		"/nick" => Err("usage: /nick username".to_string()),
+		"/join" if !argument.is_empty() => Ok(Event::JoinChannel(argument)),
+		"/part" if !argument.is_empty() => Ok(Event::PartChannel(argument)),
+		"/msg" if !argument.is_empty() && !rest.is_empty() => {
+			Ok(Event::SendChannelMessage(argument, rest))
+		}
+		"/dm" if !argument.is_empty() && !rest.is_empty() => {
+			Ok(Event::SendDirectMessage(argument, rest))
+		}
+		"/join" | "/part" => Err(format!("usage: {} #channel", command)),
+		"/msg" => Err("usage: /msg #channel message".to_string()),
+		"/dm" => Err("usage: /dm username message".to_string()),
+		_ => Err(format!("unknown command: {}", command)),
+	}
+}
@@ -1,15 +1,30 @@
+use crate::known_hosts::{self, KnownHosts};
 use crate::socket;
+use crate::tcp_client::{server::Heartbeat, InnerClient, OuterClient};
 use crate::tcp_client::{Event, Sender};
-use crate::tcp_client::{InnerClient, OuterClient};
 use eframe::egui::{self, Style, Ui, Visuals};
-use lib::io;
-use rsa::{pkcs8::FromPublicKey, RsaPublicKey};
-use tokio::sync::mpsc::{self, error::TryRecvError, UnboundedReceiver, UnboundedSender};
+use lib::encoding::HistoryEntry;
+use std::{path::Path, time::Duration};
+use tokio::sync::{
+	mpsc::{self, error::TryRecvError, UnboundedReceiver, UnboundedSender},
+	oneshot, watch,
+};
+
+mod command;
 
 const WINDOW_NAME: &str = "Chat";
 
 pub enum WindowEvent {
 	DisplayMessage(String, String),
+	Error(String),
+	Notice(String),
+	UserList(Vec<String>),
+	UserJoined(String),
+	UserLeft(String),
+	UserRenamed(String, String), // old username, new username
+	History(Vec<HistoryEntry>),  // older messages, oldest first
+	TrustServer(String, String, oneshot::Sender<bool>), // address, key fingerprint, whether the user accepted it
+	Latency(u64),                                       // round trip to the server in milliseconds
 }
 
 pub type WindowReceiver = UnboundedReceiver<WindowEvent>;
@@ -36,29 +51,83 @@ pub fn run_window(window: Application) {
 
 pub fn create_tcp_client(username: String, server: String) -> (Sender, WindowReceiver) {
 	let (window_sender, window_receiver) = create_channel();
-	let public_key = {
-		let file_content = io::read_public_key().expect("unable to find public key");
-		RsaPublicKey::from_public_key_pem(&file_content).expect("invalid public key")
-	};
 
-	let inner = InnerClient::new(window_sender);
+	let error_sender = window_sender.clone();
+	let (reconnect_sender, mut reconnect_after) = watch::channel(Duration::ZERO);
+	let (heartbeat_sender, heartbeat) = watch::channel(Heartbeat::default());
+	let inner = InnerClient::new(window_sender, reconnect_sender, heartbeat_sender);
 	let outer = OuterClient::new(inner);
 	let sender = outer.sender();
 
 	let sender_clone = sender.clone();
 	tokio::spawn(async move {
-		let mut socket = match socket::Socket::new(server, public_key) {
-			Ok(socket) => socket,
-			Err(e) => panic!("{}", e),
+		let known_hosts = match KnownHosts::open(Path::new(known_hosts::KNOWN_HOSTS)) {
+			Ok(known_hosts) => known_hosts,
+			Err(e) => {
+				let _ = error_sender.send(WindowEvent::Error(format!(
+					"unable to read {}: {}",
+					known_hosts::KNOWN_HOSTS,
+					e
+				)));
+				return;
+			}
 		};
-		let _ = socket.initalize(outer).await;
-		let _ = sender_clone.send(Event::Instantiate(username));
+		let mut socket =
+			match socket::Socket::new(server, known_hosts, error_sender.clone(), heartbeat) {
+				Ok(socket) => socket,
+				Err(e) => {
+					let _ =
+						error_sender.send(WindowEvent::Error(format!("invalid address: {}", e)));
+					return;
+				}
+			};
 
-		println!("starting socket listen...");
-		match socket.listen().await {
-			Ok(()) => println!("successfully ran and ended client"),
-			Err(e) => panic!("{}", e),
-		};
+		// the same tcp client is kept across reconnects, it resumes the session with the token the server gave it
+		let mut attempt = 0;
+		loop {
+			match socket.initalize(outer.clone()).await {
+				Ok(()) => {
+					if attempt > 0 {
+						let _ = error_sender.send(WindowEvent::Notice("reconnected".to_string()));
+					}
+					attempt = 0;
+					let _ = sender_clone.send(Event::Instantiate(username.clone()));
+
+					println!("starting socket listen...");
+					match socket.listen().await {
+						Ok(()) => {
+							let _ = error_sender.send(WindowEvent::Error(
+								"the server closed the connection".to_string(),
+							));
+						}
+						Err(e) => {
+							let _ = error_sender
+								.send(WindowEvent::Error(format!("connection lost: {}", e)));
+						}
+					};
+				}
+				Err(e) if e.is::<socket::UntrustedServer>() => {
+					let _ = error_sender.send(WindowEvent::Error(e.to_string()));
+					return;
+				}
+				Err(e) => {
+					let _ =
+						error_sender.send(WindowEvent::Error(format!("unable to connect: {}", e)));
+				}
+			}
+
+			// a server that is shutting down tells us when to come back
+			let delay = match reconnect_after.has_changed() {
+				Ok(true) => *reconnect_after.borrow_and_update(),
+				_ => socket::reconnect_delay(attempt),
+			};
+			attempt += 1;
+			let _ = error_sender.send(WindowEvent::Notice(format!(
+				"reconnecting in {} seconds...",
+				delay.as_secs()
+			)));
+			tokio::time::sleep(delay).await;
+		}
 	});
 
 	(sender, window_receiver)
@@ -72,6 +141,11 @@ pub struct Application {
 	current_message: String,
 	logged_in: bool,
 	messages: Vec<Message>,
+	users: Vec<String>,          // everyone online, kept sorted
+	oldest_history: Option<u64>, // id of the oldest message we got from the history
+	history_exhausted: bool,     // the server has nothing older
+	latency: Option<u64>,        // last round trip to the server in milliseconds
+	pending_trust: Option<(String, String, oneshot::Sender<bool>)>, // a server key waiting on the user
 	client_sender: Option<Sender>, // to send things to the tcp client
 	window_receiver: Option<WindowReceiver>, // receive message updates, etc
 }
@@ -84,6 +158,11 @@ impl Application {
 			current_message: "".to_string(),
 			logged_in: false,
 			messages: vec![],
+			users: vec![],
+			oldest_history: None,
+			history_exhausted: false,
+			latency: None,
+			pending_trust: None,
 			client_sender: None,
 			window_receiver: None,
 		}
@@ -96,12 +175,77 @@ impl Application {
 	fn set_window_receiver(&mut self, window_receiver: WindowReceiver) {
 		self.window_receiver = Some(window_receiver);
 	}
+
+	fn handle_window_event(&mut self, event: WindowEvent) {
+		match event {
+			WindowEvent::DisplayMessage(auth, cont) => self.messages.push(Message(auth, cont)),
+			WindowEvent::Error(reason) => self.messages.push(Message("Error".to_string(), reason)),
+			WindowEvent::Notice(notice) => self.messages.push(Message("*".to_string(), notice)),
+			WindowEvent::UserList(mut users) => {
+				users.sort();
+				users.dedup();
+				self.users = users;
+			}
+			WindowEvent::UserJoined(username) => self.add_user(username),
+			WindowEvent::UserLeft(username) => self.remove_user(&username),
+			WindowEvent::History(mut entries) => {
+				if entries.is_empty() {
+					self.history_exhausted = true;
+					return;
+				}
+
+				// a page can repeat what's already shown (a failed resume joins again & gets the newest page),
+				// only what is older than anything we have goes in front
+				if let Some(oldest) = self.oldest_history {
+					entries.retain(|entry| entry.id < oldest);
+				}
+				match entries.first() {
+					Some(entry) => self.oldest_history = Some(entry.id),
+					None => return,
+				}
+				let older = entries
+					.into_iter()
+					.map(|entry| Message(entry.author, entry.content));
+				self.messages.splice(0..0, older);
+			}
+			WindowEvent::Latency(latency) => self.latency = Some(latency),
+			WindowEvent::TrustServer(address, fingerprint, reply) => {
+				self.pending_trust = Some((address, fingerprint, reply))
+			}
+			WindowEvent::UserRenamed(old_username, new_username) => {
+				self.remove_user(&old_username);
+				self.messages.push(Message(
+					"*".to_string(),
+					format!("{old_username} is now known as {new_username}"),
+				));
+				self.add_user(new_username);
+			}
+		}
+	}
+
+	fn add_user(&mut self, username: String) {
+		if let Err(idx) = self.users.binary_search(&username) {
+			self.users.insert(idx, username);
+		}
+	}
+
+	fn remove_user(&mut self, username: &str) {
+		self.users.retain(|user| user != username);
+	}
 }
 
 impl Application {
 	fn render_chat(&mut self, ui: &mut Ui) {
 		egui::ScrollArea::vertical().show(ui, |ui| {
-			if self.messages.len() == 0 {
+			if let (Some(oldest), false) = (self.oldest_history, self.history_exhausted) {
+				if ui.button("Load older messages").clicked() {
+					if let Some(sender) = &self.client_sender {
+						let _ = sender.send(Event::FetchHistory(oldest));
+					}
+				}
+			}
+
+			if self.messages.is_empty() {
 				ui.label("There are no messages here currently :(");
 			} else {
 				for message in &self.messages {
@@ -123,8 +267,13 @@ impl Application {
 			// self.messages
 			// 	.push(Message("frosty".to_string(), self.current_message.clone()));
 
-			if let Some(sender) = &self.client_sender {
-				let _ = sender.send(Event::SendMessage(self.current_message.clone()));
+			match command::parse_input(&self.current_message) {
+				Ok(event) => {
+					if let Some(sender) = &self.client_sender {
+						let _ = sender.send(event);
+					}
+				}
+				Err(reason) => self.messages.push(Message("Error".to_string(), reason)),
 			}
 
 			self.current_message = "".to_string();
@@ -132,6 +281,55 @@ impl Application {
 		}
 	}
 
+	fn render_users(&mut self, ui: &mut Ui) {
+		ui.heading(format!("Online ({})", self.users.len()));
+
+		egui::ScrollArea::vertical().show(ui, |ui| {
+			for user in &self.users {
+				ui.label(user);
+			}
+		});
+	}
+
+	fn render_status(&mut self, ui: &mut Ui) {
+		let latency = match self.latency {
+			Some(latency) => format!("{latency} ms"),
+			None => "measuring...".to_string(),
+		};
+		ui.label(format!(
+			"{} as {} | latency: {}",
+			self.current_ip, self.current_username, latency
+		));
+	}
+
+	fn render_trust(&mut self, ctx: &egui::Context) {
+		let mut answer = None;
+		if let Some((address, fingerprint, _)) = &self.pending_trust {
+			egui::Window::new("Unknown server")
+				.collapsible(false)
+				.resizable(false)
+				.show(ctx, |ui| {
+					ui.label(format!("{address} has not been connected to before."));
+					ui.label("Only accept if this fingerprint matches the one the server's owner gave you:");
+					ui.monospace(fingerprint);
+					ui.horizontal(|ui| {
+						if ui.button("Accept").clicked() {
+							answer = Some(true);
+						}
+						if ui.button("Reject").clicked() {
+							answer = Some(false);
+						}
+					});
+				});
+		}
+
+		if let Some(answer) = answer {
+			if let Some((_, _, reply)) = self.pending_trust.take() {
+				let _ = reply.send(answer);
+			}
+		}
+	}
+
 	fn render_login(&mut self, ui: &mut Ui) {
 		ui.add_space(10.0);
 		ui.heading("Login");
@@ -156,19 +354,28 @@ impl Application {
 
 impl eframe::App for Application {
 	fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+		let mut events = vec![];
 		if let Some(recv) = &mut self.window_receiver {
 			loop {
 				match recv.try_recv() {
-					Ok(event) => match event {
-						WindowEvent::DisplayMessage(auth, cont) => {
-							self.messages.push(Message(auth, cont))
-						}
-					},
+					Ok(event) => events.push(event),
 					Err(TryRecvError::Empty) => break,
-					Err(TryRecvError::Disconnected) => break, // try to reconnect in the future
+					Err(TryRecvError::Disconnected) => break, // the tcp client gave up, it already said why
 				}
 			}
 		}
+		events
+			.into_iter()
+			.for_each(|event| self.handle_window_event(event));
+
+		self.render_trust(ctx);
+
+		if self.logged_in {
+			egui::TopBottomPanel::bottom("status").show(ctx, |ui| self.render_status(ui));
+			egui::SidePanel::right("users")
+				.resizable(false)
+				.show(ctx, |ui| self.render_users(ui));
+		}
 
 		egui::CentralPanel::default().show(ctx, |ui| {
 			ui.heading(WINDOW_NAME);
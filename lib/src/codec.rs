@@ -0,0 +1,179 @@
+use bytes::{Buf, BufMut, BytesMut};
+use std::{fmt, io};
+use tokio_util::codec::{Decoder, Encoder};
+
+pub const SIZE_HINT_LENGTH: usize = 8; // 64 bit size hint
+pub const ID_LENGTH: usize = 64; // 512 bit id, look at lib/src/lib.rs -> hex_hash
+pub const HEADER_LENGTH: usize = SIZE_HINT_LENGTH + ID_LENGTH;
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 1 << 22; // 4 MiB
+
+/*
+A single frame on the wire:
+size hint (64 bits) | id (512 bits) | encrypted buffer
+
+the id is the recepient when written by a client and the sender when written by the server
+*/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+	pub id: String,
+	pub data: Vec<u8>,
+}
+
+impl Frame {
+	pub fn new(id: String, data: Vec<u8>) -> Self {
+		Self { id, data }
+	}
+}
+
+#[derive(Debug)]
+pub enum FrameError {
+	Io(io::Error),
+	Oversized(usize),  // size hint over the codec's maximum frame size
+	InvalidId(String), // id which isn't utf-8 or the wrong length
+}
+
+impl fmt::Display for FrameError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			FrameError::Io(e) => write!(f, "io error: {}", e),
+			FrameError::Oversized(size) => write!(f, "frame of {} bytes is too large", size),
+			FrameError::InvalidId(reason) => write!(f, "invalid frame id: {}", reason),
+		}
+	}
+}
+
+impl std::error::Error for FrameError {}
+
+impl From<io::Error> for FrameError {
+	fn from(e: io::Error) -> Self {
+		FrameError::Io(e)
+	}
+}
+
+pub struct FrameCodec {
+	max_frame_size: usize,
+}
+
+impl FrameCodec {
+	pub fn new(max_frame_size: usize) -> Self {
+		Self { max_frame_size }
+	}
+
+	pub fn max_frame_size(&self) -> usize {
+		self.max_frame_size
+	}
+}
+
+impl Default for FrameCodec {
+	fn default() -> Self {
+		Self::new(DEFAULT_MAX_FRAME_SIZE)
+	}
+}
+
+impl Decoder for FrameCodec {
+	type Item = Frame;
+	type Error = FrameError;
+
+	fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Frame>, FrameError> {
+		if src.len() < HEADER_LENGTH {
+			src.reserve(HEADER_LENGTH - src.len());
+			return Ok(None);
+		}
+
+		let mut size_hint = [0u8; SIZE_HINT_LENGTH];
+		size_hint.copy_from_slice(&src[..SIZE_HINT_LENGTH]);
+		let size = u64::from_be_bytes(size_hint);
+		if size > self.max_frame_size as u64 {
+			return Err(FrameError::Oversized(size as usize));
+		}
+
+		// wait until the whole frame has arrived before consuming anything
+		let frame_length = HEADER_LENGTH + size as usize;
+		if src.len() < frame_length {
+			src.reserve(frame_length - src.len());
+			return Ok(None);
+		}
+
+		src.advance(SIZE_HINT_LENGTH);
+		let id = src.split_to(ID_LENGTH);
+		let data = src.split_to(size as usize);
+
+		let id =
+			String::from_utf8(id.to_vec()).map_err(|e| FrameError::InvalidId(e.to_string()))?;
+		Ok(Some(Frame::new(id, data.to_vec())))
+	}
+}
+
+impl Encoder<Frame> for FrameCodec {
+	type Error = FrameError;
+
+	fn encode(&mut self, frame: Frame, dst: &mut BytesMut) -> Result<(), FrameError> {
+		if frame.id.len() != ID_LENGTH {
+			return Err(FrameError::InvalidId(format!(
+				"expected {} bytes, got {}",
+				ID_LENGTH,
+				frame.id.len()
+			)));
+		}
+		if frame.data.len() > self.max_frame_size {
+			return Err(FrameError::Oversized(frame.data.len()));
+		}
+
+		dst.reserve(HEADER_LENGTH + frame.data.len());
+		dst.put_u64(frame.data.len() as u64);
+		dst.put_slice(frame.id.as_bytes());
+		dst.put_slice(&frame.data);
+		Ok(())
+	}
+}
+
+// encodes a single frame into a standalone buffer, ready to be written to a stream
+pub fn encode_frame(frame: Frame) -> Result<Vec<u8>, FrameError> {
+	let mut buf = BytesMut::new();
+	FrameCodec::default().encode(frame, &mut buf)?;
+	Ok(buf.to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{encode_frame, Frame, FrameCodec, FrameError, HEADER_LENGTH, ID_LENGTH};
+	use bytes::BytesMut;
+	use tokio_util::codec::Decoder;
+
+	#[test]
+	fn test_partial_and_coalesced_frames() {
+		let first = Frame::new("a".repeat(ID_LENGTH), b"hello".to_vec());
+		let second = Frame::new("b".repeat(ID_LENGTH), b"world!".to_vec());
+		let wire = [
+			encode_frame(first.clone()).unwrap(),
+			encode_frame(second.clone()).unwrap(),
+		]
+		.concat();
+
+		let mut codec = FrameCodec::default();
+		let mut buf = BytesMut::new();
+
+		// feed the first frame minus a byte, nothing should come out yet
+		let split = HEADER_LENGTH + first.data.len() - 1;
+		buf.extend_from_slice(&wire[..split]);
+		assert_eq!(codec.decode(&mut buf).unwrap(), None);
+
+		// the rest of both frames arrive in one read
+		buf.extend_from_slice(&wire[split..]);
+		assert_eq!(codec.decode(&mut buf).unwrap(), Some(first));
+		assert_eq!(codec.decode(&mut buf).unwrap(), Some(second));
+		assert_eq!(codec.decode(&mut buf).unwrap(), None);
+	}
+
+	#[test]
+	fn test_oversized_frame() {
+		let wire = encode_frame(Frame::new("a".repeat(ID_LENGTH), vec![0u8; 32])).unwrap();
+		let mut codec = FrameCodec::new(16);
+		let mut buf = BytesMut::from(&wire[..]);
+
+		assert!(matches!(
+			codec.decode(&mut buf),
+			Err(FrameError::Oversized(32))
+		));
+	}
+}
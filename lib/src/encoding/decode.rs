@@ -1,5 +1,4 @@
-use super::{Instruction, Opcodes, Reader};
-use num_traits::FromPrimitive;
+use super::{DecodeError, Instruction, Reader};
 
 pub struct Decoder {
 	reader: Reader,
@@ -7,30 +6,22 @@ pub struct Decoder {
 }
 
 impl Decoder {
-	pub fn from_bytes(buffer: Vec<u8>) -> Self {
+	pub fn from_bytes(buffer: Vec<u8>) -> Result<Self, DecodeError> {
 		let mut s = Self {
 			reader: Reader::from_bytes(buffer),
 			feed: vec![],
 		};
-		s.parse();
+		s.parse()?;
 
-		s
+		Ok(s)
 	}
 
-	fn parse(&mut self) {
+	fn parse(&mut self) -> Result<(), DecodeError> {
 		while self.reader.has_next() {
-			let opcode = self.reader.short();
-
-			let instruction = match FromPrimitive::from_u16(opcode) {
-				Some(Opcodes::Instantiate) => Instruction::Instantiate(self.reader.string()),
-				Some(Opcodes::SendMessage) => Instruction::SendMessage(self.reader.string()),
-				Some(Opcodes::ReceiveMessage) => {
-					Instruction::ReceiveMessage(self.reader.string(), self.reader.string())
-				}
-				_ => Instruction::NOP,
-			};
-
+			let instruction = Instruction::decode(&mut self.reader)?;
 			self.feed.push(instruction)
 		}
+
+		Ok(())
 	}
 }
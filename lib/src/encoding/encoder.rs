@@ -1,4 +1,4 @@
-use super::{Instruction, Opcodes, Writer};
+use super::{Instruction, Writer};
 
 pub struct Encoder {
 	pub writer: Writer,
@@ -16,22 +16,7 @@ impl Encoder {
 
 	fn parse(&mut self, feed: Vec<Instruction>) {
 		for instruction in feed {
-			match instruction {
-				Instruction::Instantiate(username) => {
-					self.writer.short(Opcodes::Instantiate as u16);
-					self.writer.string(&username);
-				}
-				Instruction::SendMessage(message) => {
-					self.writer.short(Opcodes::SendMessage as u16);
-					self.writer.string(&message);
-				}
-				Instruction::ReceiveMessage(sender, message) => {
-					self.writer.short(Opcodes::ReceiveMessage as u16);
-					self.writer.string(&sender);
-					self.writer.string(&message);
-				}
-				_ => {}
-			}
+			instruction.encode(&mut self.writer);
 		}
 	}
 }
@@ -0,0 +1,26 @@
+use std::fmt;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum DecodeError {
+	Truncated { needed: usize, remaining: usize }, // tried to read past the end of the buffer
+	BadOpcode(u16),                                // opcode which doesn't map to an instruction
+	InvalidUtf8,                                   // string field which isn't valid utf-8
+	Oversized(i64),                                // length prefix which is negative or over the limit
+}
+
+impl fmt::Display for DecodeError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			DecodeError::Truncated { needed, remaining } => write!(
+				f,
+				"truncated frame, needed {} bytes but only {} remain",
+				needed, remaining
+			),
+			DecodeError::BadOpcode(opcode) => write!(f, "unknown opcode: {}", opcode),
+			DecodeError::InvalidUtf8 => write!(f, "string is not valid utf-8"),
+			DecodeError::Oversized(len) => write!(f, "invalid length prefix: {}", len),
+		}
+	}
+}
+
+impl std::error::Error for DecodeError {}
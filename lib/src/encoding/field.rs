@@ -0,0 +1,119 @@
+use super::{DecodeError, ErrorCode, HistoryEntry, Reader, Writer};
+use num_traits::FromPrimitive;
+
+/*
+A value which can be written to & read from the wire,
+every field of an instruction has to implement this (look at lib_derive -> WireInstruction)
+*/
+pub trait WireField: Sized {
+	fn write(&self, writer: &mut Writer);
+	fn read(reader: &mut Reader) -> Result<Self, DecodeError>;
+}
+
+impl WireField for u8 {
+	fn write(&self, writer: &mut Writer) {
+		writer.byte(*self)
+	}
+
+	fn read(reader: &mut Reader) -> Result<Self, DecodeError> {
+		reader.byte()
+	}
+}
+
+impl WireField for u16 {
+	fn write(&self, writer: &mut Writer) {
+		writer.short(*self)
+	}
+
+	fn read(reader: &mut Reader) -> Result<Self, DecodeError> {
+		reader.short()
+	}
+}
+
+impl WireField for u64 {
+	fn write(&self, writer: &mut Writer) {
+		writer.u64(*self)
+	}
+
+	fn read(reader: &mut Reader) -> Result<Self, DecodeError> {
+		reader.u64()
+	}
+}
+
+impl WireField for f32 {
+	fn write(&self, writer: &mut Writer) {
+		writer.f32(*self)
+	}
+
+	fn read(reader: &mut Reader) -> Result<Self, DecodeError> {
+		reader.f32()
+	}
+}
+
+impl WireField for i32 {
+	fn write(&self, writer: &mut Writer) {
+		writer.i32(*self)
+	}
+
+	fn read(reader: &mut Reader) -> Result<Self, DecodeError> {
+		reader.i32()
+	}
+}
+
+impl WireField for String {
+	fn write(&self, writer: &mut Writer) {
+		writer.string(self)
+	}
+
+	fn read(reader: &mut Reader) -> Result<Self, DecodeError> {
+		reader.string()
+	}
+}
+
+// length prefixed like a string, so a Vec<u8> is laid out exactly the same as one
+impl<T: WireField> WireField for Vec<T> {
+	fn write(&self, writer: &mut Writer) {
+		writer.i32(self.len() as i32);
+		self.iter().for_each(|item| item.write(writer))
+	}
+
+	fn read(reader: &mut Reader) -> Result<Self, DecodeError> {
+		let len = reader.length()?;
+		// every item takes at least a byte, don't trust the length for the allocation
+		let mut items = Vec::with_capacity(len.min(reader.remaining()));
+		for _ in 0..len {
+			items.push(T::read(reader)?);
+		}
+
+		Ok(items)
+	}
+}
+
+impl WireField for ErrorCode {
+	fn write(&self, writer: &mut Writer) {
+		writer.short(*self as u16)
+	}
+
+	fn read(reader: &mut Reader) -> Result<Self, DecodeError> {
+		// codes we don't know about yet are still worth showing to the user
+		Ok(FromPrimitive::from_u16(reader.short()?).unwrap_or(ErrorCode::Unknown))
+	}
+}
+
+impl WireField for HistoryEntry {
+	fn write(&self, writer: &mut Writer) {
+		writer.u64(self.id);
+		writer.u64(self.timestamp);
+		writer.string(&self.author);
+		writer.string(&self.content);
+	}
+
+	fn read(reader: &mut Reader) -> Result<Self, DecodeError> {
+		Ok(Self {
+			id: reader.u64()?,
+			timestamp: reader.u64()?,
+			author: reader.string()?,
+			content: reader.string()?,
+		})
+	}
+}
@@ -1,8 +1,13 @@
+use lib_derive::WireInstruction;
 use num_derive::FromPrimitive;
 
+mod error;
+mod field;
 mod reader;
 mod writer;
-pub use reader::Reader;
+pub use error::DecodeError;
+pub use field::WireField;
+pub use reader::{Reader, MAX_STRING_LENGTH};
 pub use writer::Writer;
 
 mod decode;
@@ -10,25 +15,109 @@ mod encoder;
 pub use decode::Decoder;
 pub use encoder::Encoder;
 
-#[derive(Debug)]
+// the Opcodes enum & the wire format are generated by WireInstruction, look at lib_derive
+#[derive(Debug, PartialEq, WireInstruction)]
 pub enum Instruction {
+	#[opcode = 0]
 	NOP,
-	Instantiate(String),            // username
-	SendMessage(String),            // content
+	#[opcode = 1]
+	Instantiate(String), // username
+	#[opcode = 2]
+	SendMessage(String), // content
+	#[opcode = 3]
 	ReceiveMessage(String, String), // author, content
+	#[opcode = 4]
+	Hello(u16, u64), // protocol version, capability bits
+	#[opcode = 5]
+	Error(ErrorCode, String), // code, human readable reason
+	#[opcode = 6]
+	JoinChannel(String), // channel
+	#[opcode = 7]
+	PartChannel(String), // channel
+	#[opcode = 8]
+	ChannelMessage(String, String, String), // channel, author (ignored from clients), content
+	#[opcode = 9]
+	ChannelJoined(String, String), // channel, username
+	#[opcode = 10]
+	ChannelParted(String, String), // channel, username
+	#[opcode = 11]
+	DirectKeyOffer(String, String, Vec<u8>), // peer id, peer username, x25519 public key
+	#[opcode = 12]
+	DirectKeyAnswer(String, String, Vec<u8>), // peer id, peer username, x25519 public key
+	#[opcode = 13]
+	DirectMessage(String), // content, only ever sent end to end encrypted between two clients
+	#[opcode = 14]
+	ListUsers,
+	#[opcode = 15]
+	UserList(Vec<String>), // usernames of everyone online
+	#[opcode = 16]
+	UserJoined(String), // username
+	#[opcode = 17]
+	UserLeft(String), // username
+	#[opcode = 18]
+	ChangeNick(String), // new username
+	#[opcode = 19]
+	NickChanged(String, String), // old username, new username
+	#[opcode = 20]
+	FetchHistory { before: u64, limit: u16 }, // only entries older than the `before` id, 0 for the newest
+	#[opcode = 21]
+	History { entries: Vec<HistoryEntry> }, // oldest first, empty once there is nothing older
+	#[opcode = 22]
+	Rekey(u64), // generation, every frame after this one is sealed with the next key, look at lib/src/encryption.rs
+	#[opcode = 23]
+	Ping(u64), // when it was sent (look at lib/src/protocol.rs -> now_millis), answered with a Pong carrying the same value
+	#[opcode = 24]
+	Pong(u64),
+	#[opcode = 25]
+	ResumeToken(String), // given out with a username, lets a client that lost its connection pick up where it left off
+	#[opcode = 26]
+	Resume(String), // resume token, sent instead of Instantiate after a reconnect
+	#[opcode = 27]
+	ServerShutdown {
+		reason: String,
+		reconnect_after: u64,
+	}, // seconds to wait before reconnecting
+	#[opcode = 28]
+	Heartbeat { interval: u64, missed: u64 }, // seconds between pings & how many in a row may go unanswered, sent with the hello reply
+	#[opcode = 29]
+	DirectKeyRejected { username: String }, // answers a DirectKeyOffer that had nobody to go to, carries the username as offered
+	#[opcode = 30]
+	PeerGone { id: String }, // a direct frame or key answer was addressed to a client that is no longer connected
 }
 
-#[derive(FromPrimitive)]
-pub enum Opcodes {
-	NOP = 0,
-	Instantiate = 1,
-	SendMessage = 2,
-	ReceiveMessage = 3,
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromPrimitive)]
+pub enum ErrorCode {
+	Unknown = 0,
+	IncompatibleVersion = 1,
+	HelloRequired = 2,
+	UnsupportedFeature = 3,
+	InvalidChannel = 4,
+	NotInChannel = 5,
+	UnknownUser = 6,
+	UnknownRecipient = 7,
+	InvalidUsername = 8,
+	NicknameTaken = 9,
+	UsernameRequired = 10,
+	InvalidRekey = 11,
+	InvalidResume = 12,
+	MessageTooLong = 13,
+}
+
+// a message from the server's history, look at server/src/history.rs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistoryEntry {
+	pub id: u64,
+	pub timestamp: u64, // unix seconds
+	pub author: String,
+	pub content: String,
 }
 
 mod test {
 	#[cfg(test)]
-	use super::{Decoder, Encoder, Instruction, Reader, Writer};
+	use super::{
+		DecodeError, Decoder, Encoder, ErrorCode, HistoryEntry, Instruction, Opcodes, Reader,
+		Writer,
+	};
 
 	#[test]
 	fn test_encoder_and_decoder() {
@@ -38,10 +127,132 @@ mod test {
 		)];
 
 		let encoded = Encoder::from_feed(feed).writer.dump();
-		let decoded = Decoder::from_bytes(encoded).feed;
+		let decoded = Decoder::from_bytes(encoded).unwrap().feed;
 		println!("{:#?}", decoded);
 	}
 
+	#[test]
+	fn test_every_instruction_round_trips() {
+		let feed = vec![
+			Instruction::NOP,
+			Instruction::Instantiate("frosty".to_string()),
+			Instruction::SendMessage("hi".to_string()),
+			Instruction::ReceiveMessage("frosty".to_string(), "hi".to_string()),
+			Instruction::Hello(1, 0b101),
+			Instruction::Error(ErrorCode::IncompatibleVersion, "too old".to_string()),
+			Instruction::DirectKeyOffer("id".to_string(), "frosty".to_string(), vec![1, 2, 3]),
+			Instruction::UserList(vec!["frosty".to_string(), "spike".to_string()]),
+			Instruction::History {
+				entries: vec![HistoryEntry {
+					id: 1,
+					timestamp: 2,
+					author: "frosty".to_string(),
+					content: "hi".to_string(),
+				}],
+			},
+			Instruction::Ping(u64::MAX),
+			Instruction::Heartbeat {
+				interval: 30,
+				missed: 3,
+			},
+			Instruction::DirectKeyRejected {
+				username: "Frosty".to_string(),
+			},
+			Instruction::PeerGone {
+				id: "id".to_string(),
+			},
+		];
+		let opcodes: Vec<Opcodes> = feed.iter().map(|i| i.opcode()).collect();
+		assert_eq!(opcodes[3], Opcodes::ReceiveMessage);
+
+		let encoded = Encoder::from_feed(feed).writer.dump();
+		let decoded = Decoder::from_bytes(encoded).unwrap().feed;
+		assert_eq!(
+			decoded.iter().map(|i| i.opcode()).collect::<Vec<_>>(),
+			opcodes
+		);
+		assert_eq!(
+			decoded[5],
+			Instruction::Error(ErrorCode::IncompatibleVersion, "too old".to_string())
+		);
+		assert_eq!(
+			decoded[6],
+			Instruction::DirectKeyOffer("id".to_string(), "frosty".to_string(), vec![1, 2, 3])
+		);
+		assert_eq!(
+			decoded[7],
+			Instruction::UserList(vec!["frosty".to_string(), "spike".to_string()])
+		);
+		assert!(matches!(&decoded[8], Instruction::History { entries } if entries[0].id == 1));
+		assert_eq!(decoded[9], Instruction::Ping(u64::MAX));
+		assert_eq!(
+			decoded[10],
+			Instruction::Heartbeat {
+				interval: 30,
+				missed: 3
+			}
+		);
+		assert_eq!(
+			decoded[11],
+			Instruction::DirectKeyRejected {
+				username: "Frosty".to_string()
+			}
+		);
+		assert_eq!(
+			decoded[12],
+			Instruction::PeerGone {
+				id: "id".to_string()
+			}
+		);
+	}
+
+	#[test]
+	fn test_decoder_keeps_last_instruction() {
+		let feed = vec![
+			Instruction::SendMessage("a".to_string()),
+			Instruction::SendMessage("b".to_string()),
+		];
+
+		let encoded = Encoder::from_feed(feed).writer.dump();
+		let decoded = Decoder::from_bytes(encoded).unwrap().feed;
+		assert_eq!(decoded.len(), 2);
+	}
+
+	#[test]
+	fn test_decoder_errors() {
+		let encoded = Encoder::from_feed(vec![Instruction::SendMessage("hello".to_string())])
+			.writer
+			.dump();
+		assert!(matches!(
+			Decoder::from_bytes(encoded[..encoded.len() - 1].to_vec()),
+			Err(DecodeError::Truncated { .. })
+		));
+
+		let mut writer = Writer::new();
+		writer.short(u16::MAX);
+		assert!(matches!(
+			Decoder::from_bytes(writer.dump()),
+			Err(DecodeError::BadOpcode(u16::MAX))
+		));
+
+		let mut writer = Writer::new();
+		writer.short(2);
+		writer.i32(-1);
+		assert!(matches!(
+			Decoder::from_bytes(writer.dump()),
+			Err(DecodeError::Oversized(-1))
+		));
+
+		let mut writer = Writer::new();
+		writer.short(2);
+		writer.i32(2);
+		writer.bytes(&[0xc3, 0x28]);
+		assert!(matches!(
+			Decoder::from_bytes(writer.dump()),
+			Err(DecodeError::InvalidUtf8)
+		));
+	}
+
 	#[test]
 	fn test_writer_and_reader() {
 		let mut writer = Writer::new();
@@ -54,12 +265,12 @@ mod test {
 
 		let mut reader = Reader::from_bytes(writer.dump());
 
-		print!("{} ", reader.u64());
-		print!("{} ", reader.byte());
-		print!("{} ", reader.short());
-		print!("{} ", reader.f32());
-		print!("{} ", reader.i32());
-		print!("{} ", reader.string());
-		println!();
+		assert_eq!(reader.u64(), Ok(5000000));
+		assert_eq!(reader.byte(), Ok(200));
+		assert_eq!(reader.short(), Ok(500));
+		assert_eq!(reader.f32(), Ok(100.52));
+		assert_eq!(reader.i32(), Ok(500));
+		assert_eq!(reader.string(), Ok("spike stinks".to_string()));
+		assert!(!reader.has_next());
 	}
 }
@@ -1,48 +1,81 @@
+use super::DecodeError;
+
+// largest length prefix we will accept for a single string, anything bigger is treated as garbage
+pub const MAX_STRING_LENGTH: usize = 1 << 20;
+
 pub struct Reader {
-    buffer: Vec<u8>,
-    idx: usize,
+	buffer: Vec<u8>,
+	idx: usize,
 }
 
 impl Reader {
-    pub fn from_bytes(bytes: Vec<u8>) -> Self {
-        Self {
-            buffer: bytes,
-            idx: 0,
-        }
-    }
-
-    pub fn has_next(&self) -> bool {
-        return self.idx + 1 < self.buffer.len() - 1;
-    }
-
-    pub fn byte(&mut self) -> u8 {
-        self.idx += 1;
-        self.buffer[self.idx - 1]
-    }
-
-    pub fn bytes(&mut self, n: usize) -> &[u8] {
-        self.idx += n;
-        &self.buffer[self.idx - n..self.idx]
-    }
-
-    pub fn short(&mut self) -> u16 {
-        (self.byte() as u16) + ((self.byte() as u16) << 8)
-    }
-
-    pub fn u64(&mut self) -> u64 {
-        u64::from_be_bytes(self.bytes(8).try_into().unwrap())
-    }
-
-    pub fn f32(&mut self) -> f32 {
-        f32::from_be_bytes(self.bytes(4).try_into().unwrap())
-    }
-
-    pub fn i32(&mut self) -> i32 {
-        i32::from_be_bytes(self.bytes(4).try_into().unwrap())
-    }
-
-    pub fn string(&mut self) -> String {
-        let len = self.i32() as usize;
-        String::from_utf8(self.bytes(len).to_vec()).unwrap()
-    }
+	pub fn from_bytes(bytes: Vec<u8>) -> Self {
+		Self {
+			buffer: bytes,
+			idx: 0,
+		}
+	}
+
+	pub fn has_next(&self) -> bool {
+		self.idx < self.buffer.len()
+	}
+
+	pub fn remaining(&self) -> usize {
+		self.buffer.len() - self.idx
+	}
+
+	pub fn byte(&mut self) -> Result<u8, DecodeError> {
+		Ok(self.bytes(1)?[0])
+	}
+
+	pub fn bytes(&mut self, n: usize) -> Result<&[u8], DecodeError> {
+		if n > self.remaining() {
+			return Err(DecodeError::Truncated {
+				needed: n,
+				remaining: self.remaining(),
+			});
+		}
+
+		self.idx += n;
+		Ok(&self.buffer[self.idx - n..self.idx])
+	}
+
+	// reads exactly N bytes into a fixed size array
+	fn array<const N: usize>(&mut self) -> Result<[u8; N], DecodeError> {
+		let mut arr = [0u8; N];
+		arr.copy_from_slice(self.bytes(N)?);
+		Ok(arr)
+	}
+
+	pub fn short(&mut self) -> Result<u16, DecodeError> {
+		Ok(u16::from_le_bytes(self.array()?))
+	}
+
+	pub fn u64(&mut self) -> Result<u64, DecodeError> {
+		Ok(u64::from_be_bytes(self.array()?))
+	}
+
+	pub fn f32(&mut self) -> Result<f32, DecodeError> {
+		Ok(f32::from_be_bytes(self.array()?))
+	}
+
+	pub fn i32(&mut self) -> Result<i32, DecodeError> {
+		Ok(i32::from_be_bytes(self.array()?))
+	}
+
+	// i32 length prefix of a string or byte buffer
+	pub fn length(&mut self) -> Result<usize, DecodeError> {
+		let len = self.i32()?;
+		if len < 0 || len as usize > MAX_STRING_LENGTH {
+			return Err(DecodeError::Oversized(len as i64));
+		}
+
+		Ok(len as usize)
+	}
+
+	pub fn string(&mut self) -> Result<String, DecodeError> {
+		let len = self.length()?;
+		let bytes = self.bytes(len)?.to_vec();
+		String::from_utf8(bytes).map_err(|_| DecodeError::InvalidUtf8)
+	}
 }
@@ -1,45 +1,373 @@
+use crate::encoding::{Encoder, Instruction};
 use aes_gcm::{
-	aead::{rand_core::RngCore, Aead, KeyInit, OsRng},
+	aead::{rand_core::RngCore, Aead, KeyInit, OsRng, Payload},
 	Aes256Gcm, Nonce,
 };
+use hkdf::Hkdf;
+use sha3::Sha3_256;
+use std::{
+	fmt,
+	time::{Duration, Instant},
+};
+use zeroize::Zeroize;
+
+pub const SEQUENCE_LENGTH: usize = 8; // 64 bit sequence number
+pub const NONCE_LENGTH: usize = 12; // 96 bit nonce
+pub const TAG_LENGTH: usize = 16; // 128 bit GCM tag
+const REKEY_LENGTH: usize = 2 + 8; // opcode & generation of the Rekey instruction seal may tack on
+
+// the most a Session adds to the data it seals, look at Sealer::seal
+pub const SEAL_OVERHEAD: usize = SEQUENCE_LENGTH + NONCE_LENGTH + TAG_LENGTH + REKEY_LENGTH;
+
+// a sending key is rotated after whichever of these comes first
+pub const REKEY_AFTER_FRAMES: u64 = 1 << 16;
+pub const REKEY_AFTER: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum CryptoError {
+	// buffer too small to even hold the sequence & nonce
+	Truncated(usize),
+	// sequence number we already saw or one that skipped ahead
+	Replayed { expected: u64, got: u64 },
+	// authentication failed, the key, header or data is wrong
+	Forged,
+	// a rekey that skipped a generation (or went back)
+	UnexpectedRekey { expected: u64, got: u64 },
+	// a key that isn't 256 bits
+	InvalidKey,
+}
+
+impl fmt::Display for CryptoError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			CryptoError::Truncated(size) => {
+				write!(f, "encrypted buffer of {} bytes is too small", size)
+			}
+			CryptoError::Replayed { expected, got } => write!(
+				f,
+				"replayed or reordered frame, expected sequence {} but got {}",
+				expected, got
+			),
+			CryptoError::Forged => write!(f, "unable to authenticate frame"),
+			CryptoError::InvalidKey => write!(f, "key is not 256 bits"),
+			CryptoError::UnexpectedRekey { expected, got } => write!(
+				f,
+				"expected a rekey to generation {} but got {}",
+				expected, got
+			),
+		}
+	}
+}
+
+impl std::error::Error for CryptoError {}
 
 pub fn generate_nonce() -> Vec<u8> {
-	let mut buff = [0u8; 12]; // 96 bit nonce / 8
+	let mut buff = [0u8; NONCE_LENGTH];
 	OsRng.fill_bytes(&mut buff);
 	buff.to_vec()
 }
 
-pub fn encrypt(key_buff: &[u8], data: &[u8]) -> Vec<u8> {
+// data the tag covers without it being encrypted
+pub fn encrypt(key_buff: &[u8], data: &[u8], associated_data: &[u8]) -> Vec<u8> {
 	let mut nonce_buff = generate_nonce();
 	let cipher = Aes256Gcm::new_from_slice(key_buff).expect("invalid key");
 	let nonce = Nonce::from_slice(nonce_buff.as_slice());
 
-	let mut ciphered = cipher.encrypt(nonce, data).expect("failed to encrypt");
+	let payload = Payload {
+		msg: data,
+		aad: associated_data,
+	};
+	let mut ciphered = cipher.encrypt(nonce, payload).expect("failed to encrypt");
 
 	// append the nonce to the beginning of the ciphered buffer
 	nonce_buff.append(&mut ciphered);
 	nonce_buff
 }
 
-pub fn decrypt(key_buff: &[u8], buffer: Vec<u8>) -> Vec<u8> {
-	let (nonce_buff, cipher_text) = buffer.split_at(12);
+pub fn decrypt(
+	key_buff: &[u8],
+	buffer: &[u8],
+	associated_data: &[u8],
+) -> Result<Vec<u8>, CryptoError> {
+	if buffer.len() < NONCE_LENGTH + TAG_LENGTH {
+		return Err(CryptoError::Truncated(buffer.len()));
+	}
+	let (nonce_buff, cipher_text) = buffer.split_at(NONCE_LENGTH);
 
 	let nonce = Nonce::from_slice(nonce_buff);
-	let cipher = Aes256Gcm::new_from_slice(key_buff).expect("invalid key");
+	let cipher = Aes256Gcm::new_from_slice(key_buff).map_err(|_| CryptoError::InvalidKey)?;
+
+	let payload = Payload {
+		msg: cipher_text,
+		aad: associated_data,
+	};
+	cipher
+		.decrypt(nonce, payload)
+		.map_err(|_| CryptoError::Forged)
+}
 
-	match cipher.decrypt(nonce, cipher_text.as_ref()) {
-		Ok(data) => data,
-		Err(e) => panic!("unable to decrypt: {:#?}", e.to_string()),
+/*
+Everything in a frame that isn't encrypted but still has to be trusted:
+who it is from, who it is to, its sequence number & the size hint in the frame header
+
+the routing id in the header is one of the two ids, so changing it (or sending a frame back to whoever wrote it) breaks the tag
+*/
+fn associated_data(from: &str, to: &str, sequence: u64, size: usize) -> Vec<u8> {
+	[
+		from.as_bytes(),
+		to.as_bytes(),
+		&sequence.to_be_bytes(),
+		&(size as u64).to_be_bytes(),
+	]
+	.concat()
+}
+
+/*
+Key material which is wiped from memory once dropped & never shows up in logs,
+deliberately not Clone so there is only ever one copy of it to wipe
+*/
+pub struct SecretKey(Vec<u8>);
+
+impl SecretKey {
+	pub fn new(bytes: Vec<u8>) -> Self {
+		Self(bytes)
+	}
+
+	pub fn as_bytes(&self) -> &[u8] {
+		&self.0
+	}
+}
+
+impl Drop for SecretKey {
+	fn drop(&mut self) {
+		self.0.zeroize();
+	}
+}
+
+impl fmt::Debug for SecretKey {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "SecretKey({} bytes)", self.0.len())
+	}
+}
+
+// the next key of one direction, HKDF over the current key so an old key can't be worked out from a newer one
+fn ratchet(key: &SecretKey, from: &str, to: &str, generation: u64) -> SecretKey {
+	let info = [
+		b"irc-chat rekey".as_slice(),
+		from.as_bytes(),
+		to.as_bytes(),
+		&generation.to_be_bytes(),
+	]
+	.concat();
+
+	let mut next = vec![0u8; 32];
+	Hkdf::<Sha3_256>::new(None, key.as_bytes())
+		.expand(&info, &mut next)
+		.expect("32 bytes is a valid HKDF output length");
+	SecretKey::new(next)
+}
+
+/*
+One end of an encrypted link, either client <-> server or client <-> client for direct messages
+each direction counts its own frames so a captured frame can't be replayed or moved around
+
+both directions start off with their own key from the handshake (look at lib/src/handshake.rs) & ratchet forward on their own,
+the frame sealed with the last use of a key carries a Rekey instruction telling the other end to ratchet too
+
+encrypted buffer: sequence (64 bits) | nonce (96 bits) | cipher text + tag
+*/
+pub struct Session {
+	sealer: Sealer,
+	opener: Opener,
+}
+
+impl Session {
+	pub fn new(
+		send_key: SecretKey,
+		receive_key: SecretKey,
+		local_id: String,
+		remote_id: String,
+	) -> Self {
+		Self {
+			sealer: Sealer::new(send_key, local_id.clone(), remote_id.clone()),
+			opener: Opener::new(receive_key, local_id, remote_id),
+		}
+	}
+
+	// for when the two directions are used from different tasks
+	pub fn split(self) -> (Sealer, Opener) {
+		(self.sealer, self.opener)
+	}
+
+	pub fn needs_rekey(&self) -> bool {
+		self.sealer.needs_rekey()
+	}
+
+	pub fn seal(&mut self, data: &[u8]) -> Vec<u8> {
+		self.sealer.seal(data)
+	}
+
+	pub fn rekey_receive(&mut self, generation: u64) -> Result<(), CryptoError> {
+		self.opener.rekey(generation)
+	}
+
+	pub fn open(&mut self, buffer: &[u8]) -> Result<Vec<u8>, CryptoError> {
+		self.opener.open(buffer)
+	}
+}
+
+// the sending half of a Session
+pub struct Sealer {
+	key: SecretKey,
+	local_id: String,
+	remote_id: String,
+	sequence: u64,
+	generation: u64,
+	sent_with_key: u64, // frames sealed with the current key
+	key_since: Instant, // when the current key was made
+}
+
+impl Sealer {
+	fn new(key: SecretKey, local_id: String, remote_id: String) -> Self {
+		Self {
+			key,
+			local_id,
+			remote_id,
+			sequence: 0,
+			generation: 0,
+			sent_with_key: 0,
+			key_since: Instant::now(),
+		}
+	}
+
+	pub fn needs_rekey(&self) -> bool {
+		self.sent_with_key >= REKEY_AFTER_FRAMES || self.key_since.elapsed() >= REKEY_AFTER
+	}
+
+	/*
+	Whether `len` bytes are still at most `max` once sealed, a rekey tacked on included
+
+	asked before sealing, every seal takes a sequence number,
+	so a frame sealed but never sent leaves a gap the other end can't get past
+	*/
+	pub fn fits(len: usize, max: usize) -> bool {
+		len.saturating_add(SEAL_OVERHEAD) <= max
+	}
+
+	// a due rekey is tacked onto the end of the data, so the other end ratchets right after reading it
+	pub fn seal(&mut self, data: &[u8]) -> Vec<u8> {
+		if !self.needs_rekey() {
+			return self.seal_with_current_key(data);
+		}
+
+		let rekey = Encoder::from_feed(vec![Instruction::Rekey(self.generation + 1)])
+			.writer
+			.dump();
+		let buffer = self.seal_with_current_key(&[data, &rekey].concat());
+		self.rekey();
+		buffer
+	}
+
+	fn seal_with_current_key(&mut self, data: &[u8]) -> Vec<u8> {
+		let sequence = self.sequence;
+		self.sequence += 1;
+		self.sent_with_key += 1;
+
+		let size = SEQUENCE_LENGTH + NONCE_LENGTH + data.len() + TAG_LENGTH;
+		let associated_data = associated_data(&self.local_id, &self.remote_id, sequence, size);
+
+		let mut buffer = sequence.to_be_bytes().to_vec();
+		buffer.append(&mut encrypt(self.key.as_bytes(), data, &associated_data));
+		buffer
+	}
+
+	fn rekey(&mut self) {
+		self.generation += 1;
+		// the old key is wiped as it is dropped
+		self.key = ratchet(&self.key, &self.local_id, &self.remote_id, self.generation);
+		self.sent_with_key = 0;
+		self.key_since = Instant::now();
+	}
+}
+
+// the receiving half of a Session
+pub struct Opener {
+	key: SecretKey,
+	local_id: String,
+	remote_id: String,
+	sequence: u64,
+	generation: u64,
+}
+
+impl Opener {
+	fn new(key: SecretKey, local_id: String, remote_id: String) -> Self {
+		Self {
+			key,
+			local_id,
+			remote_id,
+			sequence: 0,
+			generation: 0,
+		}
+	}
+
+	// called when the other end told us (with a Rekey instruction) that it moved to the next generation
+	pub fn rekey(&mut self, generation: u64) -> Result<(), CryptoError> {
+		if generation != self.generation + 1 {
+			return Err(CryptoError::UnexpectedRekey {
+				expected: self.generation + 1,
+				got: generation,
+			});
+		}
+
+		self.generation = generation;
+		self.key = ratchet(&self.key, &self.remote_id, &self.local_id, self.generation);
+		Ok(())
+	}
+
+	pub fn open(&mut self, buffer: &[u8]) -> Result<Vec<u8>, CryptoError> {
+		if buffer.len() < SEQUENCE_LENGTH {
+			return Err(CryptoError::Truncated(buffer.len()));
+		}
+		let (sequence_buff, encrypted) = buffer.split_at(SEQUENCE_LENGTH);
+		let sequence = u64::from_be_bytes(sequence_buff.try_into().expect("8 bytes"));
+
+		// strictly one after the other, TCP already keeps them in order
+		if sequence != self.sequence {
+			return Err(CryptoError::Replayed {
+				expected: self.sequence,
+				got: sequence,
+			});
+		}
+
+		let associated_data =
+			associated_data(&self.remote_id, &self.local_id, sequence, buffer.len());
+		let data = decrypt(self.key.as_bytes(), encrypted, &associated_data)?;
+
+		// only counted once it is authentic, garbage can't push the sequence forward
+		self.sequence += 1;
+		Ok(data)
 	}
 }
 
 #[cfg(test)]
 mod tests {
-	use crate::encryption::decrypt;
-
-	use super::encrypt;
+	use super::{
+		decrypt, encrypt, CryptoError, Sealer, SecretKey, Session, REKEY_AFTER_FRAMES,
+		SEAL_OVERHEAD,
+	};
+	use crate::encoding::{Decoder, Instruction};
 	use aes_gcm::{aead::OsRng, Aes256Gcm, KeyInit};
 
+	// the same key both ways keeps the tests short
+	fn session(key: &[u8], local_id: &str, remote_id: &str) -> Session {
+		Session::new(
+			SecretKey::new(key.to_vec()),
+			SecretKey::new(key.to_vec()),
+			local_id.to_string(),
+			remote_id.to_string(),
+		)
+	}
+
 	#[test]
 	pub fn test_encryption() {
 		let key = Aes256Gcm::generate_key(&mut OsRng);
@@ -47,12 +375,100 @@ mod tests {
 		let slice_key = key.as_slice();
 		let data = b"hello world";
 
-		let ciphered = encrypt(slice_key, data);
-		let plain = decrypt(slice_key, ciphered.clone());
+		let ciphered = encrypt(slice_key, data, b"header");
+		let plain = decrypt(slice_key, &ciphered, b"header").unwrap();
 
 		assert_eq!(
 			String::from_utf8(data.to_vec()).unwrap(),
 			String::from_utf8(plain).unwrap()
 		);
+		assert_eq!(
+			decrypt(slice_key, &ciphered, b"tampered"),
+			Err(CryptoError::Forged)
+		);
+		assert_eq!(
+			decrypt(&slice_key[1..], &ciphered, b"header"),
+			Err(CryptoError::InvalidKey)
+		);
+	}
+
+	#[test]
+	pub fn test_session_rejects_replays_and_reflection() {
+		let key = Aes256Gcm::generate_key(&mut OsRng).to_vec();
+		let mut client = session(&key, "client", "server");
+		let mut server = session(&key, "server", "client");
+
+		let first = client.seal(b"first");
+		let second = client.seal(b"second");
+
+		// out of order
+		assert_eq!(
+			server.open(&second),
+			Err(CryptoError::Replayed {
+				expected: 0,
+				got: 1
+			})
+		);
+		assert_eq!(server.open(&first).unwrap(), b"first");
+		assert_eq!(server.open(&second).unwrap(), b"second");
+
+		// the same frame again
+		assert!(matches!(
+			server.open(&second),
+			Err(CryptoError::Replayed { .. })
+		));
+
+		// a frame sent back to whoever wrote it
+		let reply = server.seal(b"reply");
+		let mut reflected = session(&key, "server", "client");
+		assert_eq!(reflected.open(&reply), Err(CryptoError::Forged));
+		assert_eq!(client.open(&reply).unwrap(), b"reply");
+
+		// a frame claiming to be from someone else
+		let mut spoofed = session(&key, "spoofed", "server");
+		spoofed.sealer.sequence = 2;
+		let third = spoofed.seal(b"third");
+		assert_eq!(server.open(&third), Err(CryptoError::Forged));
+	}
+
+	#[test]
+	pub fn test_session_rekey() {
+		let key = Aes256Gcm::generate_key(&mut OsRng).to_vec();
+		let mut client = session(&key, "client", "server");
+		let mut server = session(&key, "server", "client");
+
+		client.sealer.sent_with_key = REKEY_AFTER_FRAMES;
+		assert!(client.needs_rekey());
+
+		// the last frame under the old key carries the rekey
+		let sealed = client.seal(&[]);
+		assert_eq!(sealed.len(), SEAL_OVERHEAD);
+		assert!(Sealer::fits(0, sealed.len()) && !Sealer::fits(1, sealed.len()));
+		let feed = Decoder::from_bytes(server.open(&sealed).unwrap())
+			.unwrap()
+			.feed;
+		assert_eq!(feed, vec![Instruction::Rekey(1)]);
+		assert!(!client.needs_rekey());
+		assert_ne!(client.sealer.key.as_bytes(), key);
+
+		// the next frame is only readable once the server ratcheted as well
+		let sealed = client.seal(b"new key");
+		let mut stale = session(&key, "server", "client");
+		stale.opener.sequence = 1;
+		assert_eq!(stale.open(&sealed), Err(CryptoError::Forged));
+
+		server.rekey_receive(1).unwrap();
+		assert_eq!(server.open(&sealed).unwrap(), b"new key");
+		assert_eq!(server.opener.key.as_bytes(), client.sealer.key.as_bytes());
+
+		// the other direction is untouched
+		assert_eq!(server.sealer.key.as_bytes(), key);
+		assert_eq!(
+			server.rekey_receive(3),
+			Err(CryptoError::UnexpectedRekey {
+				expected: 2,
+				got: 3
+			})
+		);
 	}
 }
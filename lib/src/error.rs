@@ -0,0 +1,75 @@
+use crate::{codec::FrameError, encoding::DecodeError, encryption::CryptoError};
+use std::{fmt, io};
+
+/*
+Everything that can go wrong with a single connection,
+whoever gets one of these drops that connection & keeps serving everyone else
+*/
+#[derive(Debug)]
+pub enum Error {
+	Io(io::Error),
+	Frame(FrameError),
+	Decode(DecodeError),
+	Crypto(CryptoError),
+	Handshake(String), // key exchange went wrong, the reason is from rsa/pkcs8 or our own checks
+}
+
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Error::Io(e) => write!(f, "io error: {}", e),
+			Error::Frame(e) => write!(f, "framing error: {}", e),
+			Error::Decode(e) => write!(f, "decode error: {}", e),
+			Error::Crypto(e) => write!(f, "crypto error: {}", e),
+			Error::Handshake(reason) => write!(f, "handshake failed: {}", reason),
+		}
+	}
+}
+
+impl std::error::Error for Error {
+	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+		match self {
+			Error::Io(e) => Some(e),
+			Error::Frame(e) => Some(e),
+			Error::Decode(e) => Some(e),
+			Error::Crypto(e) => Some(e),
+			Error::Handshake(_) => None,
+		}
+	}
+}
+
+impl From<io::Error> for Error {
+	fn from(e: io::Error) -> Self {
+		Error::Io(e)
+	}
+}
+
+impl From<FrameError> for Error {
+	fn from(e: FrameError) -> Self {
+		Error::Frame(e)
+	}
+}
+
+impl From<DecodeError> for Error {
+	fn from(e: DecodeError) -> Self {
+		Error::Decode(e)
+	}
+}
+
+impl From<CryptoError> for Error {
+	fn from(e: CryptoError) -> Self {
+		Error::Crypto(e)
+	}
+}
+
+impl From<rsa::errors::Error> for Error {
+	fn from(e: rsa::errors::Error) -> Self {
+		Error::Handshake(e.to_string())
+	}
+}
+
+impl From<rsa::pkcs8::Error> for Error {
+	fn from(e: rsa::pkcs8::Error) -> Self {
+		Error::Handshake(e.to_string())
+	}
+}
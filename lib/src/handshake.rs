@@ -0,0 +1,110 @@
+use crate::{encryption::SecretKey, hash};
+use hkdf::Hkdf;
+use rsa::{errors::Error, Hash, PaddingScheme, PublicKey, RsaPrivateKey, RsaPublicKey};
+use sha3::Sha3_256;
+
+// keeps a handshake signature from ever being valid for anything else
+const TRANSCRIPT_LABEL: &[u8] = b"irc-chat handshake v1";
+
+/*
+Everything both sides saw during the key exchange, hashed
+the server signs this with its long-term key so a MITM can't swap out its DH public key
+*/
+pub fn transcript_hash(
+	client_public: &[u8],
+	client_id: &[u8],
+	server_public: &[u8],
+	server_id: &[u8],
+) -> Vec<u8> {
+	let transcript = [
+		TRANSCRIPT_LABEL,
+		client_public,
+		client_id,
+		server_public,
+		server_id,
+	]
+	.concat();
+	hash(&transcript)
+}
+
+// one key per direction so the two ends never encrypt under the same key
+#[derive(Debug)]
+pub struct SessionKeys {
+	pub client_to_server: SecretKey,
+	pub server_to_client: SecretKey,
+}
+
+/*
+HKDF over the raw DH output, salted with the transcript so the keys are tied to this exact handshake
+for direct messages the peer who offered their key takes the client's side
+*/
+pub fn derive_session_keys(shared_secret: &[u8], transcript: &[u8]) -> SessionKeys {
+	let hkdf = Hkdf::<Sha3_256>::new(Some(transcript), shared_secret);
+	let expand = |info: &[u8]| {
+		let mut key = vec![0u8; 32];
+		hkdf.expand(info, &mut key)
+			.expect("32 bytes is a valid HKDF output length");
+		SecretKey::new(key)
+	};
+
+	SessionKeys {
+		client_to_server: expand(b"irc-chat client to server"),
+		server_to_client: expand(b"irc-chat server to client"),
+	}
+}
+
+fn padding() -> PaddingScheme {
+	PaddingScheme::new_pkcs1v15_sign(Some(Hash::SHA3_256))
+}
+
+pub fn sign_transcript(key: &RsaPrivateKey, transcript: &[u8]) -> Result<Vec<u8>, Error> {
+	key.sign(padding(), transcript)
+}
+
+pub fn verify_transcript(
+	key: &RsaPublicKey,
+	transcript: &[u8],
+	signature: &[u8],
+) -> Result<(), Error> {
+	key.verify(padding(), transcript, signature)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{derive_session_keys, sign_transcript, transcript_hash, verify_transcript};
+	use rsa::RsaPrivateKey;
+
+	#[test]
+	fn test_transcript_signature() {
+		let key = RsaPrivateKey::new(&mut rand::rngs::OsRng, 1024).unwrap();
+		let public_key = key.to_public_key();
+
+		let transcript = transcript_hash(b"client key", b"client", b"server key", b"server");
+		let signature = sign_transcript(&key, &transcript).unwrap();
+		assert!(verify_transcript(&public_key, &transcript, &signature).is_ok());
+
+		// a swapped server key changes the transcript
+		let tampered = transcript_hash(b"client key", b"client", b"mitm key", b"server");
+		assert!(verify_transcript(&public_key, &tampered, &signature).is_err());
+
+		let other = RsaPrivateKey::new(&mut rand::rngs::OsRng, 1024).unwrap();
+		assert!(verify_transcript(&other.to_public_key(), &transcript, &signature).is_err());
+	}
+
+	#[test]
+	fn test_session_keys() {
+		let keys = derive_session_keys(b"shared", b"transcript");
+		assert_eq!(keys.client_to_server.as_bytes().len(), 32);
+		assert_ne!(
+			keys.client_to_server.as_bytes(),
+			keys.server_to_client.as_bytes()
+		);
+
+		let other = derive_session_keys(b"shared", b"another transcript");
+		assert_ne!(
+			keys.client_to_server.as_bytes(),
+			other.client_to_server.as_bytes()
+		);
+		assert!(!format!("{:?}", keys).contains(&format!("{:?}", keys.client_to_server.as_bytes())));
+	}
+}
@@ -1,8 +1,15 @@
+// lets lib_derive refer to `::lib` from within this crate too
+extern crate self as lib;
+
+pub use error::Error;
 use sha3::{Digest, Sha3_256};
+pub mod codec;
 pub mod encoding;
 pub mod encryption;
+pub mod error;
+pub mod handshake;
 pub mod io;
-pub mod stream;
+pub mod protocol;
 
 pub fn hash(data: &[u8]) -> Vec<u8> {
 	Sha3_256::new_with_prefix(data).finalize().to_vec()
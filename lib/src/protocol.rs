@@ -0,0 +1,165 @@
+use std::{
+	fmt,
+	ops::BitAnd,
+	time::{SystemTime, UNIX_EPOCH},
+};
+
+// bump whenever the wire format of an existing instruction changes
+pub const PROTOCOL_VERSION: u16 = 1;
+// oldest version we still know how to talk to
+pub const MIN_PROTOCOL_VERSION: u16 = 1;
+
+pub const MAX_CHANNEL_NAME_LENGTH: usize = 32;
+pub const MAX_USERNAME_LENGTH: usize = 32;
+// upper bounds for the heartbeat a server asks for, a timer this far out is as good as none
+pub const MAX_HEARTBEAT_INTERVAL: u64 = 60 * 60;
+pub const MAX_MISSED_HEARTBEATS: u32 = 100;
+// bytes of a single chat message, small enough that it always fits in a frame once it is sent on to everyone
+pub const MAX_MESSAGE_LENGTH: usize = 1 << 16;
+
+// channels look like "#rust": a leading '#' followed by at least one visible character
+pub fn is_valid_channel_name(name: &str) -> bool {
+	name.len() > 1
+		&& name.len() <= MAX_CHANNEL_NAME_LENGTH
+		&& name.starts_with('#')
+		&& name
+			.chars()
+			.skip(1)
+			.all(|c| c.is_ascii_graphic() && c != '#')
+}
+
+// usernames can't be mistaken for channels or the server & are compared case-insensitively
+pub fn is_valid_username(name: &str) -> bool {
+	!name.is_empty()
+		&& name.chars().count() <= MAX_USERNAME_LENGTH
+		&& !name.starts_with('#')
+		&& !name.eq_ignore_ascii_case("server")
+		&& name.chars().all(|c| !c.is_whitespace() && !c.is_control())
+}
+
+pub fn same_username(a: &str, b: &str) -> bool {
+	a.to_lowercase() == b.to_lowercase()
+}
+
+// what a Ping carries, only ever compared with the clock of whoever sent it
+pub fn now_millis() -> u64 {
+	SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.map(|d| d.as_millis() as u64)
+		.unwrap_or_default()
+}
+
+/*
+Optional features a peer may support, advertised as a bit set during the hello exchange,
+only the features both sides advertise are enabled for the session
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Capabilities(u64);
+
+impl Capabilities {
+	pub const COMPRESSION: Capabilities = Capabilities(1 << 0);
+	pub const CHANNELS: Capabilities = Capabilities(1 << 1);
+	pub const ATTACHMENTS: Capabilities = Capabilities(1 << 2);
+
+	pub const fn empty() -> Self {
+		Self(0)
+	}
+
+	pub const fn from_bits(bits: u64) -> Self {
+		Self(bits)
+	}
+
+	pub const fn bits(&self) -> u64 {
+		self.0
+	}
+
+	pub const fn with(self, other: Capabilities) -> Self {
+		Self(self.0 | other.0)
+	}
+
+	pub const fn contains(&self, other: Capabilities) -> bool {
+		self.0 & other.0 == other.0
+	}
+}
+
+impl BitAnd for Capabilities {
+	type Output = Capabilities;
+
+	fn bitand(self, rhs: Capabilities) -> Capabilities {
+		Capabilities(self.0 & rhs.0)
+	}
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum NegotiationError {
+	TooOld(u16), // the peer's version is below MIN_PROTOCOL_VERSION
+}
+
+impl fmt::Display for NegotiationError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			NegotiationError::TooOld(version) => write!(
+				f,
+				"protocol version {} is no longer supported, the minimum is {}",
+				version, MIN_PROTOCOL_VERSION
+			),
+		}
+	}
+}
+
+impl std::error::Error for NegotiationError {}
+
+/*
+Picks the version & features used for a session,
+a newer peer is downgraded to our version while an older one is talked to in its own version as long as we still support it
+*/
+pub fn negotiate(
+	version: u16,
+	capabilities: Capabilities,
+	supported: Capabilities,
+) -> Result<(u16, Capabilities), NegotiationError> {
+	if version < MIN_PROTOCOL_VERSION {
+		return Err(NegotiationError::TooOld(version));
+	}
+
+	Ok((version.min(PROTOCOL_VERSION), capabilities & supported))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{
+		is_valid_channel_name, is_valid_username, negotiate, same_username, Capabilities,
+		NegotiationError, PROTOCOL_VERSION,
+	};
+
+	#[test]
+	fn test_usernames() {
+		assert!(is_valid_username("frosty"));
+		assert!(!is_valid_username(""));
+		assert!(!is_valid_username("#frosty"));
+		assert!(!is_valid_username("Server"));
+		assert!(!is_valid_username("two words"));
+		assert!(same_username("Frosty", "frosty"));
+	}
+
+	#[test]
+	fn test_channel_names() {
+		assert!(is_valid_channel_name("#rust"));
+		assert!(!is_valid_channel_name("#"));
+		assert!(!is_valid_channel_name("rust"));
+		assert!(!is_valid_channel_name("#two words"));
+		assert!(!is_valid_channel_name(&format!("#{}", "a".repeat(32))));
+	}
+
+	#[test]
+	fn test_negotiate() {
+		let theirs = Capabilities::CHANNELS.with(Capabilities::COMPRESSION);
+		let ours = Capabilities::CHANNELS.with(Capabilities::ATTACHMENTS);
+
+		let (version, capabilities) = negotiate(PROTOCOL_VERSION + 1, theirs, ours).unwrap();
+		assert_eq!(version, PROTOCOL_VERSION);
+		assert_eq!(capabilities, Capabilities::CHANNELS);
+
+		assert_eq!(negotiate(0, theirs, ours), Err(NegotiationError::TooOld(0)));
+	}
+}
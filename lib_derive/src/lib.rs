@@ -0,0 +1,176 @@
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::{format_ident, quote};
+use std::collections::HashMap;
+use syn::{
+	parse_macro_input, spanned::Spanned, Data, DeriveInput, Error, Expr, ExprLit, Fields, Lit,
+	Meta, Variant,
+};
+
+/*
+Generates the wire format for an instruction enum:
+	- an `Opcodes` enum mirroring every variant with its opcode
+	- `opcode`, `encode` & `decode` on the enum itself
+
+every variant needs an `#[opcode = N]` attribute and every field has to implement lib::encoding::WireField,
+fields are written in declaration order right after the opcode
+*/
+#[proc_macro_derive(WireInstruction, attributes(opcode))]
+pub fn derive_wire_instruction(input: TokenStream) -> TokenStream {
+	let input = parse_macro_input!(input as DeriveInput);
+	match expand(input) {
+		Ok(tokens) => tokens.into(),
+		Err(e) => e.to_compile_error().into(),
+	}
+}
+
+fn expand(input: DeriveInput) -> Result<TokenStream2, Error> {
+	let name = &input.ident;
+	let data = match &input.data {
+		Data::Enum(data) => data,
+		_ => {
+			return Err(Error::new(
+				Span::call_site(),
+				"WireInstruction can only be derived for enums",
+			))
+		}
+	};
+
+	let mut seen: HashMap<u16, &Variant> = HashMap::new();
+	let mut opcode_variants = vec![];
+	let mut from_u16_arms = vec![];
+	let mut opcode_arms = vec![];
+	let mut encode_arms = vec![];
+	let mut decode_arms = vec![];
+
+	for variant in &data.variants {
+		let opcode = opcode_of(variant)?;
+		if let Some(other) = seen.insert(opcode, variant) {
+			return Err(Error::new(
+				variant.span(),
+				format!("opcode {} is already used by {}", opcode, other.ident),
+			));
+		}
+
+		let ident = &variant.ident;
+		opcode_variants.push(quote! { #ident = #opcode });
+		from_u16_arms.push(quote! { #opcode => Some(Opcodes::#ident) });
+
+		match &variant.fields {
+			Fields::Unit => {
+				opcode_arms.push(quote! { #name::#ident => Opcodes::#ident });
+				encode_arms.push(quote! { #name::#ident => {} });
+				decode_arms.push(quote! { Opcodes::#ident => #name::#ident });
+			}
+			Fields::Unnamed(fields) => {
+				let bindings: Vec<_> = (0..fields.unnamed.len())
+					.map(|i| format_ident!("field_{}", i))
+					.collect();
+				let types = fields.unnamed.iter().map(|f| &f.ty);
+
+				opcode_arms.push(quote! { #name::#ident(..) => Opcodes::#ident });
+				encode_arms.push(quote! {
+					#name::#ident(#(#bindings),*) => {
+						#(::lib::encoding::WireField::write(#bindings, writer);)*
+					}
+				});
+				decode_arms.push(quote! {
+					Opcodes::#ident => #name::#ident(
+						#(<#types as ::lib::encoding::WireField>::read(reader)?),*
+					)
+				});
+			}
+			Fields::Named(fields) => {
+				let idents: Vec<_> = fields
+					.named
+					.iter()
+					.map(|f| f.ident.clone().expect("named field"))
+					.collect();
+				let types = fields.named.iter().map(|f| &f.ty);
+
+				opcode_arms.push(quote! { #name::#ident { .. } => Opcodes::#ident });
+				encode_arms.push(quote! {
+					#name::#ident { #(#idents),* } => {
+						#(::lib::encoding::WireField::write(#idents, writer);)*
+					}
+				});
+				decode_arms.push(quote! {
+					Opcodes::#ident => #name::#ident {
+						#(#idents: <#types as ::lib::encoding::WireField>::read(reader)?),*
+					}
+				});
+			}
+		}
+	}
+
+	Ok(quote! {
+		#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+		#[repr(u16)]
+		pub enum Opcodes {
+			#(#opcode_variants),*
+		}
+
+		impl Opcodes {
+			pub fn from_u16(opcode: u16) -> Option<Self> {
+				match opcode {
+					#(#from_u16_arms,)*
+					_ => None,
+				}
+			}
+		}
+
+		impl #name {
+			pub fn opcode(&self) -> Opcodes {
+				match self {
+					#(#opcode_arms),*
+				}
+			}
+
+			pub fn encode(&self, writer: &mut ::lib::encoding::Writer) {
+				writer.short(self.opcode() as u16);
+				match self {
+					#(#encode_arms)*
+				}
+			}
+
+			pub fn decode(
+				reader: &mut ::lib::encoding::Reader,
+			) -> Result<Self, ::lib::encoding::DecodeError> {
+				let opcode = reader.short()?;
+				let opcode = Opcodes::from_u16(opcode)
+					.ok_or(::lib::encoding::DecodeError::BadOpcode(opcode))?;
+
+				Ok(match opcode {
+					#(#decode_arms),*
+				})
+			}
+		}
+	})
+}
+
+fn opcode_of(variant: &Variant) -> Result<u16, Error> {
+	for attr in &variant.attrs {
+		if !attr.path().is_ident("opcode") {
+			continue;
+		}
+
+		if let Meta::NameValue(meta) = &attr.meta {
+			if let Expr::Lit(ExprLit {
+				lit: Lit::Int(int), ..
+			}) = &meta.value
+			{
+				return int.base10_parse::<u16>();
+			}
+		}
+
+		return Err(Error::new(
+			attr.span(),
+			"expected an opcode in the form #[opcode = N]",
+		));
+	}
+
+	Err(Error::new(
+		variant.span(),
+		format!("{} is missing an #[opcode = N] attribute", variant.ident),
+	))
+}
@@ -0,0 +1,409 @@
+use clap::{Parser, Subcommand};
+use lib::{
+	codec::DEFAULT_MAX_FRAME_SIZE,
+	protocol::{MAX_HEARTBEAT_INTERVAL, MAX_MISSED_HEARTBEATS},
+};
+use serde::Deserialize;
+use std::{
+	fmt, fs, io,
+	net::SocketAddr,
+	path::{Path, PathBuf},
+	time::Duration,
+};
+
+// looked for in the working directory when no --config is given
+pub const DEFAULT_CONFIG: &str = "server.toml";
+// passphrase of an encrypted private key, kept out of the config & command line on purpose
+pub const PASSPHRASE_ENV: &str = "IRC_KEY_PASSPHRASE";
+// seconds, for every other timer in the config
+const MAX_TIMER: u64 = 24 * 60 * 60;
+
+#[derive(Parser, Debug)]
+#[command(about = "IRC chat server")]
+pub struct Args {
+	#[command(subcommand)]
+	pub command: Option<Command>,
+	/// path to the TOML config, defaults to server.toml if it exists
+	#[arg(short, long)]
+	pub config: Option<PathBuf>,
+	/// address to listen on, can be given more than once (replaces the config's list)
+	#[arg(short, long)]
+	pub listen: Vec<String>,
+	/// PKCS#8 PEM private key
+	#[arg(long)]
+	pub private_key: Option<PathBuf>,
+	/// message shown to everyone who joins
+	#[arg(long)]
+	pub motd: Option<String>,
+	/// append-only message history log
+	#[arg(long)]
+	pub history: Option<PathBuf>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+	/// generate a new RSA key pair, the public half is written next to it as <out>.pub
+	Keygen {
+		#[arg(short, long, default_value_t = 2048)]
+		bits: usize,
+		#[arg(short, long, default_value = lib::io::PRIVATE_KEY)]
+		out: PathBuf,
+		/// encrypt the private key with the passphrase in $IRC_KEY_PASSPHRASE
+		#[arg(long)]
+		encrypt: bool,
+		/// overwrite an existing key
+		#[arg(long)]
+		force: bool,
+	},
+	/// print the fingerprint of a public or private key, the configured private key by default
+	Fingerprint { key: Option<PathBuf> },
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+	pub listen: Vec<String>,
+	pub private_key: PathBuf,
+	pub motd: Option<String>,
+	pub limits: Limits,
+	pub storage: Storage,
+	pub shutdown: Shutdown,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+	pub max_frame_size: usize,   // bytes, look at lib/src/codec.rs
+	pub max_clients: usize,      // connections past this are dropped right away
+	pub history_on_join: usize,  // messages replayed to someone who just joined
+	pub write_queue: usize,      // frames waiting to be written to a single client
+	pub max_dropped: usize,      // frames a slow client may miss before it is disconnected
+	pub heartbeat_interval: u64, // seconds of silence before we ping a client
+	pub missed_heartbeats: u32,  // pings in a row a client may leave unanswered before it is dropped
+	pub resume_grace: u64, // seconds a client which lost its connection has to come back before anyone is told it left
+	pub stats_interval: u64, // seconds between write queue stats in the log, 0 turns them off
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Storage {
+	pub history: PathBuf,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Shutdown {
+	pub deadline: u64, // seconds the clients get to be sent what is still queued for them
+	pub reconnect_after: u64, // seconds clients are told to wait before reconnecting
+}
+
+impl Default for Config {
+	fn default() -> Self {
+		Self {
+			listen: vec!["0.0.0.0:8080".to_string()],
+			private_key: PathBuf::from(lib::io::PRIVATE_KEY),
+			motd: None,
+			limits: Limits::default(),
+			storage: Storage::default(),
+			shutdown: Shutdown::default(),
+		}
+	}
+}
+
+impl Default for Limits {
+	fn default() -> Self {
+		Self {
+			max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+			max_clients: 256,
+			history_on_join: 50,
+			write_queue: 256,
+			max_dropped: 64,
+			heartbeat_interval: 30,
+			missed_heartbeats: 3,
+			resume_grace: 60,
+			stats_interval: 60,
+		}
+	}
+}
+
+impl Limits {
+	pub fn heartbeat_interval(&self) -> Duration {
+		Duration::from_secs(self.heartbeat_interval)
+	}
+
+	// how long a client can stay silent before it is considered gone
+	pub fn heartbeat_timeout(&self) -> Duration {
+		self.heartbeat_interval() * self.missed_heartbeats
+	}
+
+	pub fn resume_grace(&self) -> Duration {
+		Duration::from_secs(self.resume_grace)
+	}
+
+	pub fn stats_interval(&self) -> Option<Duration> {
+		match self.stats_interval {
+			0 => None,
+			seconds => Some(Duration::from_secs(seconds)),
+		}
+	}
+}
+
+impl Default for Shutdown {
+	fn default() -> Self {
+		Self {
+			deadline: 10,
+			reconnect_after: 30,
+		}
+	}
+}
+
+impl Default for Storage {
+	fn default() -> Self {
+		Self {
+			history: PathBuf::from("history.log"),
+		}
+	}
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+	Io(PathBuf, io::Error),
+	Parse(PathBuf, toml::de::Error),
+	Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			ConfigError::Io(path, e) => write!(f, "unable to read {}: {}", path.display(), e),
+			ConfigError::Parse(path, e) => write!(f, "invalid config {}: {}", path.display(), e),
+			ConfigError::Invalid(reason) => write!(f, "invalid config: {}", reason),
+		}
+	}
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+	pub fn from_file(path: &Path) -> Result<Self, ConfigError> {
+		let content =
+			fs::read_to_string(path).map_err(|e| ConfigError::Io(path.to_path_buf(), e))?;
+		toml::from_str(&content).map_err(|e| ConfigError::Parse(path.to_path_buf(), e))
+	}
+
+	// the config file (if any) with the command line layered on top, checked before the server uses it
+	pub fn load(args: &Args) -> Result<Self, ConfigError> {
+		let config = Self::from_args(args)?;
+		config.validate()?;
+		Ok(config)
+	}
+
+	// what load gives back without validating it, for commands that only need part of it
+	pub fn from_args(args: &Args) -> Result<Self, ConfigError> {
+		let mut config = match &args.config {
+			Some(path) => Self::from_file(path)?,
+			None if Path::new(DEFAULT_CONFIG).exists() => {
+				Self::from_file(Path::new(DEFAULT_CONFIG))?
+			}
+			None => Self::default(),
+		};
+
+		if !args.listen.is_empty() {
+			config.listen = args.listen.clone();
+		}
+		if let Some(private_key) = &args.private_key {
+			config.private_key = private_key.clone();
+		}
+		if let Some(motd) = &args.motd {
+			config.motd = Some(motd.clone());
+		}
+		if let Some(history) = &args.history {
+			config.storage.history = history.clone();
+		}
+
+		Ok(config)
+	}
+
+	pub fn validate(&self) -> Result<(), ConfigError> {
+		if self.listen.is_empty() {
+			return Err(ConfigError::Invalid(
+				"at least one listen address is needed".to_string(),
+			));
+		}
+		for address in &self.listen {
+			if address.parse::<SocketAddr>().is_err() {
+				return Err(ConfigError::Invalid(format!(
+					"listen address {:?} is not an ip:port pair",
+					address
+				)));
+			}
+		}
+
+		if !self.private_key.is_file() {
+			return Err(ConfigError::Invalid(format!(
+				"private key {} does not exist",
+				self.private_key.display()
+			)));
+		}
+
+		// clients (and our own writer, look at lib/src/codec.rs -> encode_frame) never go past the default
+		if self.limits.max_frame_size == 0 || self.limits.max_frame_size > DEFAULT_MAX_FRAME_SIZE {
+			return Err(ConfigError::Invalid(format!(
+				"limits.max_frame_size must be between 1 and {DEFAULT_MAX_FRAME_SIZE}"
+			)));
+		}
+		if self.limits.max_clients == 0 {
+			return Err(ConfigError::Invalid(
+				"limits.max_clients must be above 0".to_string(),
+			));
+		}
+		if self.limits.write_queue == 0 {
+			return Err(ConfigError::Invalid(
+				"limits.write_queue must be above 0".to_string(),
+			));
+		}
+		if self.limits.heartbeat_interval == 0 || self.limits.missed_heartbeats == 0 {
+			return Err(ConfigError::Invalid(
+				"limits.heartbeat_interval & limits.missed_heartbeats must be above 0".to_string(),
+			));
+		}
+		// the timers are added to the current time, anything bigger could overflow
+		if self.limits.heartbeat_interval > MAX_HEARTBEAT_INTERVAL
+			|| self.limits.missed_heartbeats > MAX_MISSED_HEARTBEATS
+		{
+			return Err(ConfigError::Invalid(format!(
+				"limits.heartbeat_interval can be at most {MAX_HEARTBEAT_INTERVAL} & limits.missed_heartbeats at most {MAX_MISSED_HEARTBEATS}"
+			)));
+		}
+		if self.limits.resume_grace > MAX_TIMER || self.limits.stats_interval > MAX_TIMER {
+			return Err(ConfigError::Invalid(format!(
+				"limits.resume_grace & limits.stats_interval can be at most {MAX_TIMER}"
+			)));
+		}
+
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{Args, Config, ConfigError};
+	use clap::Parser;
+	use lib::codec::DEFAULT_MAX_FRAME_SIZE;
+	use std::path::PathBuf;
+
+	// any file will do as the private key, validate only checks that it is there
+	fn config() -> Config {
+		Config {
+			private_key: PathBuf::from(concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.toml")),
+			..Config::default()
+		}
+	}
+
+	// a config file in the temp dir, unique per test
+	fn write_config(name: &str, content: &str) -> PathBuf {
+		let path = std::env::temp_dir().join(format!("irc-{}-{}.toml", name, std::process::id()));
+		std::fs::write(&path, content).unwrap();
+		path
+	}
+
+	#[test]
+	fn test_flags_override_the_file() {
+		let key = config().private_key;
+		let path = write_config(
+			"merge",
+			&format!(
+				"listen = [\"127.0.0.1:1\"]\nprivate_key = {:?}\nmotd = \"from the file\"\n\n[limits]\nmax_clients = 3\n",
+				key
+			),
+		);
+
+		let args = Args::parse_from([
+			"server",
+			"--config",
+			path.to_str().unwrap(),
+			"--listen",
+			"127.0.0.1:2",
+			"--motd",
+			"from the flags",
+		]);
+		let config = Config::load(&args).unwrap();
+		assert_eq!(config.listen, vec!["127.0.0.1:2".to_string()]);
+		assert_eq!(config.motd.as_deref(), Some("from the flags"));
+		// left alone by the flags
+		assert_eq!(config.private_key, key);
+		assert_eq!(config.limits.max_clients, 3);
+		assert_eq!(config.limits.write_queue, 256);
+
+		let _ = std::fs::remove_file(&path);
+	}
+
+	#[test]
+	fn test_from_args_skips_validation() {
+		let path = write_config("unchecked", "private_key = \"missing.pem\"\n");
+		let args = Args::parse_from(["server", "--config", path.to_str().unwrap()]);
+		assert_eq!(
+			Config::from_args(&args).unwrap().private_key,
+			PathBuf::from("missing.pem")
+		);
+		assert!(matches!(Config::load(&args), Err(ConfigError::Invalid(..))));
+
+		let _ = std::fs::remove_file(&path);
+	}
+
+	#[test]
+	fn test_load_rejects_bad_files() {
+		let unknown = write_config("unknown", "[limits]\nmax_clinets = 3\n");
+		let args = Args::parse_from(["server", "--config", unknown.to_str().unwrap()]);
+		assert!(matches!(Config::load(&args), Err(ConfigError::Parse(..))));
+		let _ = std::fs::remove_file(&unknown);
+
+		let missing = std::env::temp_dir().join("irc-no-such-config.toml");
+		let args = Args::parse_from(["server", "--config", missing.to_str().unwrap()]);
+		assert!(matches!(Config::load(&args), Err(ConfigError::Io(..))));
+	}
+
+	#[test]
+	fn test_validate() {
+		let mut no_listen = config();
+		no_listen.listen.clear();
+		assert!(no_listen.validate().is_err());
+
+		let mut bad_listen = config();
+		bad_listen.listen = vec!["localhost".to_string()];
+		assert!(bad_listen.validate().is_err());
+
+		let mut no_key = config();
+		no_key.private_key = PathBuf::from("no-such-key");
+		assert!(no_key.validate().is_err());
+
+		let mut frame = config();
+		frame.limits.max_frame_size = DEFAULT_MAX_FRAME_SIZE;
+		assert!(frame.validate().is_ok());
+		frame.limits.max_frame_size = DEFAULT_MAX_FRAME_SIZE + 1;
+		assert!(frame.validate().is_err());
+		frame.limits.max_frame_size = 0;
+		assert!(frame.validate().is_err());
+
+		let mut no_queue = config();
+		no_queue.limits.write_queue = 0;
+		assert!(no_queue.validate().is_err());
+	}
+
+	#[test]
+	fn test_timers_are_bounded() {
+		assert!(config().validate().is_ok());
+
+		let mut too_long = config();
+		too_long.limits.heartbeat_interval = u64::MAX;
+		assert!(too_long.validate().is_err());
+
+		let mut too_many = config();
+		too_many.limits.missed_heartbeats = u32::MAX;
+		assert!(too_many.validate().is_err());
+
+		let mut too_late = config();
+		too_late.limits.resume_grace = u64::MAX;
+		assert!(too_late.validate().is_err());
+	}
+}
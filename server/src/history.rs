@@ -0,0 +1,228 @@
+use lib::encoding::{HistoryEntry, Reader, WireField, Writer};
+use std::{
+	fs::{self, File, OpenOptions},
+	io::{self, ErrorKind, Write},
+	path::Path,
+	time::{SystemTime, UNIX_EPOCH},
+};
+
+// most entries a client can ask for at once
+pub const MAX_HISTORY_PAGE: usize = 100;
+// encoded bytes in a single page, well under what fits in a frame
+const MAX_HISTORY_PAGE_SIZE: usize = 1 << 20;
+
+/*
+Somewhere to keep every message sent to everyone,
+so late joiners and restarted clients can page back through what they missed
+*/
+pub trait HistoryStore: Send {
+	fn append(&mut self, author: &str, content: &str) -> io::Result<HistoryEntry>;
+	// up to `limit` entries older than the `before` id (0 for the newest), oldest first
+	fn page(&self, before: u64, limit: usize) -> Vec<HistoryEntry>;
+	fn flush(&mut self) -> io::Result<()>;
+}
+
+fn now() -> u64 {
+	SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.map(|d| d.as_secs())
+		.unwrap_or_default()
+}
+
+fn page(entries: &[HistoryEntry], before: u64, limit: usize) -> Vec<HistoryEntry> {
+	let end = match before {
+		0 => entries.len(),
+		before => entries.partition_point(|entry| entry.id < before),
+	};
+
+	// newest first until the page is full, the client asks for whatever is older next
+	let mut size = 0;
+	let mut page = vec![];
+	for entry in entries[..end]
+		.iter()
+		.rev()
+		.take(limit.min(MAX_HISTORY_PAGE))
+	{
+		let mut writer = Writer::new();
+		entry.write(&mut writer);
+		size += writer.dump().len();
+		// a single entry always goes out, it came in as one message so it fits in a frame
+		if size > MAX_HISTORY_PAGE_SIZE && !page.is_empty() {
+			break;
+		}
+		page.push(entry.clone());
+	}
+	page.reverse();
+	page
+}
+
+// history which is gone once the server stops
+#[derive(Default)]
+pub struct MemoryHistory {
+	entries: Vec<HistoryEntry>,
+}
+
+impl HistoryStore for MemoryHistory {
+	fn append(&mut self, author: &str, content: &str) -> io::Result<HistoryEntry> {
+		let entry = HistoryEntry {
+			id: self.entries.last().map_or(1, |last| last.id + 1),
+			timestamp: now(),
+			author: author.to_string(),
+			content: content.to_string(),
+		};
+		self.entries.push(entry.clone());
+		Ok(entry)
+	}
+
+	fn page(&self, before: u64, limit: usize) -> Vec<HistoryEntry> {
+		page(&self.entries, before, limit)
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		Ok(())
+	}
+}
+
+/*
+Append-only log on disk, every entry is written in the same encoding as on the wire (lib/src/encoding/field.rs)
+the whole log is read back into memory when opened,
+each entry goes to the file in a single unbuffered write so a crash loses at most the one being written
+*/
+pub struct FileHistory {
+	memory: MemoryHistory,
+	file: File,
+}
+
+impl FileHistory {
+	pub fn open(path: &Path) -> io::Result<Self> {
+		let buffer = match fs::read(path) {
+			Ok(buffer) => buffer,
+			Err(e) if e.kind() == ErrorKind::NotFound => vec![],
+			Err(e) => return Err(e),
+		};
+
+		let mut entries = vec![];
+		let total = buffer.len();
+		let mut good = 0; // end of the last entry read back whole
+		let mut reader = Reader::from_bytes(buffer);
+		while reader.has_next() {
+			match HistoryEntry::read(&mut reader) {
+				Ok(entry) => {
+					entries.push(entry);
+					good = total - reader.remaining();
+				}
+				Err(e) => {
+					// most likely a write cut short by a crash, everything before it is still fine
+					eprintln!("ignoring the end of the history log: {}", e);
+					break;
+				}
+			}
+		}
+
+		let file = OpenOptions::new().create(true).append(true).open(path)?;
+		// new entries go right after the last good one, anything appended after the garbage could never be read back
+		if good < total {
+			file.set_len(good as u64)?;
+		}
+		Ok(Self {
+			memory: MemoryHistory { entries },
+			file,
+		})
+	}
+}
+
+impl HistoryStore for FileHistory {
+	fn append(&mut self, author: &str, content: &str) -> io::Result<HistoryEntry> {
+		let entry = self.memory.append(author, content)?;
+
+		let mut writer = Writer::new();
+		entry.write(&mut writer);
+		self.file.write_all(&writer.dump())?;
+
+		Ok(entry)
+	}
+
+	fn page(&self, before: u64, limit: usize) -> Vec<HistoryEntry> {
+		self.memory.page(before, limit)
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		self.file.flush()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{FileHistory, HistoryStore, MemoryHistory, MAX_HISTORY_PAGE_SIZE};
+
+	#[test]
+	fn test_file_history_reloads_and_pages() {
+		let path = std::env::temp_dir().join(format!("irc-history-{}.log", std::process::id()));
+		let _ = std::fs::remove_file(&path);
+
+		let mut history = FileHistory::open(&path).unwrap();
+		for i in 0..5 {
+			history.append("frosty", &format!("message {}", i)).unwrap();
+		}
+		history.flush().unwrap();
+		drop(history);
+
+		let history = FileHistory::open(&path).unwrap();
+		let newest = history.page(0, 2);
+		assert_eq!(newest.len(), 2);
+		assert_eq!(newest[1].content, "message 4");
+
+		let older = history.page(newest[0].id, 10);
+		assert_eq!(older.len(), 3);
+		assert_eq!(older[0].id, 1);
+
+		let _ = std::fs::remove_file(&path);
+	}
+
+	#[test]
+	fn test_page_is_capped_by_size() {
+		let mut history = MemoryHistory::default();
+		let content = "a".repeat(MAX_HISTORY_PAGE_SIZE / 4);
+		for _ in 0..10 {
+			history.append("frosty", &content).unwrap();
+		}
+
+		// only 3 of them fit with the ids & authors on top
+		let newest = history.page(0, 10);
+		assert_eq!(newest.len(), 3);
+		assert_eq!(newest[2].id, 10);
+		assert_eq!(history.page(newest[0].id, 10)[2].id, 7);
+	}
+
+	#[test]
+	fn test_file_history_drops_torn_tail() {
+		let path =
+			std::env::temp_dir().join(format!("irc-history-torn-{}.log", std::process::id()));
+		let _ = std::fs::remove_file(&path);
+
+		let mut history = FileHistory::open(&path).unwrap();
+		history.append("frosty", "kept").unwrap();
+		history.flush().unwrap();
+		drop(history);
+
+		// half of an entry, like a crash in the middle of a write
+		let mut file = std::fs::OpenOptions::new()
+			.append(true)
+			.open(&path)
+			.unwrap();
+		std::io::Write::write_all(&mut file, &[0, 0, 0]).unwrap();
+		drop(file);
+
+		let mut history = FileHistory::open(&path).unwrap();
+		history.append("frosty", "after the crash").unwrap();
+		history.flush().unwrap();
+		drop(history);
+
+		let history = FileHistory::open(&path).unwrap();
+		let entries = history.page(0, 10);
+		assert_eq!(entries.len(), 2);
+		assert_eq!(entries[1].content, "after the crash");
+
+		let _ = std::fs::remove_file(&path);
+	}
+}
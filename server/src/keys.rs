@@ -0,0 +1,87 @@
+use lib::io;
+use rand::rngs::OsRng;
+use rsa::{
+	pkcs8::{ToPrivateKey, ToPublicKey},
+	RsaPrivateKey,
+};
+use std::{error::Error, fs, io::Write, path::Path};
+
+// anything smaller is trivially breakable, anything bigger takes forever to generate
+const MIN_KEY_BITS: usize = 2048;
+const MAX_KEY_BITS: usize = 8192;
+
+// writes <out> (PKCS#8, encrypted if there is a passphrase) and <out>.pub (SPKI)
+pub fn keygen(
+	bits: usize,
+	out: &Path,
+	passphrase: Option<&str>,
+	force: bool,
+) -> Result<(), Box<dyn Error>> {
+	if !(MIN_KEY_BITS..=MAX_KEY_BITS).contains(&bits) {
+		return Err(
+			format!("key size must be between {MIN_KEY_BITS} and {MAX_KEY_BITS} bits").into(),
+		);
+	}
+	if passphrase == Some("") {
+		return Err("the passphrase can not be empty".into());
+	}
+
+	let public_out = {
+		let mut name = out.as_os_str().to_owned();
+		name.push(".pub");
+		std::path::PathBuf::from(name)
+	};
+	for path in [out, public_out.as_path()] {
+		if path.exists() && !force {
+			return Err(format!(
+				"{} already exists, pass --force to overwrite it",
+				path.display()
+			)
+			.into());
+		}
+	}
+
+	println!("generating a {bits} bit key, this can take a while...");
+	let key = RsaPrivateKey::new(&mut OsRng, bits)?;
+	let private_pem = match passphrase {
+		Some(passphrase) => key.to_pkcs8_encrypted_pem(OsRng, passphrase)?,
+		None => key.to_pkcs8_pem()?,
+	};
+	let public_key = key.to_public_key();
+
+	write_private(out, private_pem.as_bytes())?;
+	fs::write(&public_out, public_key.to_public_key_pem()?)?;
+
+	println!("wrote {} and {}", out.display(), public_out.display());
+	println!("fingerprint: {}", io::fingerprint(&public_key)?);
+	Ok(())
+}
+
+// only the owner can read the private key, an unencrypted one is as good as the key itself
+fn write_private(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+	let mut options = fs::OpenOptions::new();
+	options.write(true).create(true).truncate(true);
+	#[cfg(unix)]
+	{
+		use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+		options.mode(0o600);
+
+		// the mode is only used for new files, one overwritten with --force keeps its old permissions
+		if path.exists() {
+			fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+		}
+	}
+
+	options.open(path)?.write_all(contents)
+}
+
+// the path can hold either half of the key pair
+pub fn print_fingerprint(path: &Path, passphrase: Option<&str>) -> Result<(), Box<dyn Error>> {
+	let public_key = match io::read_public_key(path) {
+		Ok(public_key) => public_key,
+		Err(_) => io::read_private_key(path, passphrase)?.to_public_key(),
+	};
+
+	println!("{}", io::fingerprint(&public_key)?);
+	Ok(())
+}
@@ -1,33 +1,139 @@
-use lib::io;
-use rsa::{pkcs8::FromPrivateKey, PublicKeyParts, RsaPrivateKey};
+use clap::Parser;
+use config::{Args, Command, Config, PASSPHRASE_ENV};
+use history::FileHistory;
+use lib::{hex_hash, io};
+use rsa::PublicKeyParts;
+use std::{sync::Arc, time::Duration};
+use tokio::{sync::oneshot, time::timeout};
 
+pub mod config;
+pub mod history;
+pub mod keys;
 pub mod server;
 pub mod socket;
 
-const ADDRESS: &str = "0.0.0.0:8080";
-
 #[tokio::main]
 async fn main() {
-	let private_key = {
-		let content = io::read_private_key().expect("unable to get private key");
-		RsaPrivateKey::from_pkcs8_pem(&content).expect("invalid private key")
+	let args = Args::parse();
+	let passphrase = std::env::var(PASSPHRASE_ENV).ok();
+	let result = match &args.command {
+		Some(Command::Keygen {
+			bits,
+			out,
+			encrypt,
+			force,
+		}) => Some(match (*encrypt, passphrase.as_deref()) {
+			// never fall back to writing the key in the clear when it was asked to be encrypted
+			(true, None) => Err(format!("--encrypt needs a passphrase in {PASSPHRASE_ENV}").into()),
+			(encrypt, passphrase) => {
+				keys::keygen(*bits, out, passphrase.filter(|_| encrypt), *force)
+			}
+		}),
+		// the key the server would load, unless one is named
+		Some(Command::Fingerprint { key }) => Some(match key {
+			Some(path) => keys::print_fingerprint(path, passphrase.as_deref()),
+			None => Config::from_args(&args)
+				.map_err(|e| e.into())
+				.and_then(|config| {
+					keys::print_fingerprint(&config.private_key, passphrase.as_deref())
+				}),
+		}),
+		None => None,
 	};
+	if let Some(result) = result {
+		if let Err(e) = result {
+			eprintln!("{}", e);
+			std::process::exit(1);
+		}
+		return;
+	}
+
+	let config = match Config::load(&args) {
+		Ok(config) => config,
+		Err(e) => {
+			eprintln!("{}", e);
+			std::process::exit(1);
+		}
+	};
+
+	let private_key = io::read_private_key(&config.private_key, passphrase.as_deref())
+		.expect("unable to get private key");
 	println!(
 		"key size is: {} ({} bits)",
 		private_key.size(),
 		private_key.size() * 8
 	);
+	let private_key = Arc::new(private_key);
 
-	let inner = server::InnerServer::new();
+	let history = FileHistory::open(&config.storage.history).expect("unable to open history");
+
+	let inner = server::InnerServer::new(&config, Box::new(history));
 	let outer = server::OuterServer::new(inner);
 
-	let socket = match socket::Socket::new(ADDRESS.to_string(), private_key).await {
-		Ok(socket) => socket,
-		Err(e) => panic!("{}", e),
-	};
+	let mut sockets = vec![];
+	for address in &config.listen {
+		match socket::Socket::new(address.clone(), private_key.clone()).await {
+			Ok(socket) => sockets.push(socket),
+			Err(e) => panic!("unable to listen on {}: {}", address, e),
+		};
+	}
+
+	// the server is identified by the first address it listens on
+	let my_addr = sockets[0].local_addr().expect("ur invalid").to_string();
+	let me = hex_hash(my_addr.as_bytes());
+	println!("server is {}", me);
+	outer
+		.send(server::Event::SetServerId(me))
+		.expect("broker is not running");
+
+	let listeners = sockets.iter().map(|socket| socket.listen(outer.clone()));
+	tokio::select! {
+		result = futures::future::try_join_all(listeners) => {
+			if let Err(e) = result {
+				panic!("{}", e)
+			}
+		}
+		signal = shutdown_signal() => println!("got {signal}, shutting down..."),
+	}
+	// nothing new is accepted once the listeners are gone
+	drop(sockets);
+
+	let (drained, flushed) = oneshot::channel();
+	let reason = "the server is shutting down".to_string();
+	if outer
+		.send(server::Event::Shutdown(
+			reason,
+			config.shutdown.reconnect_after,
+			drained,
+		))
+		.is_err()
+	{
+		return eprintln!("broker is not running");
+	}
+
+	let deadline = Duration::from_secs(config.shutdown.deadline);
+	match timeout(deadline, flushed).await {
+		Ok(_) => println!("successfully ran and ended server"),
+		Err(_) => eprintln!("gave up on flushing clients after {:?}", deadline),
+	}
+}
+
+// SIGINT anywhere, SIGTERM as well on unix
+async fn shutdown_signal() -> &'static str {
+	#[cfg(unix)]
+	{
+		use tokio::signal::unix::{signal, SignalKind};
+
+		let mut terminate = signal(SignalKind::terminate()).expect("unable to listen for SIGTERM");
+		tokio::select! {
+			_ = tokio::signal::ctrl_c() => "SIGINT",
+			_ = terminate.recv() => "SIGTERM",
+		}
+	}
 
-	match socket.listen(outer).await {
-		Ok(()) => println!("successfully ran and ended server"),
-		Err(e) => panic!("{}", e),
+	#[cfg(not(unix))]
+	{
+		let _ = tokio::signal::ctrl_c().await;
+		"SIGINT"
 	}
 }
@@ -1,84 +1,120 @@
 use super::Sender;
-use super::{
-	client::{listen_client, Client},
-	Event, InnerServer, Receiver,
+use super::{client::Client, Event, InnerServer, Receiver};
+use crate::config::Limits;
+use lib::{
+	encoding::Instruction,
+	handshake::{derive_session_keys, sign_transcript, transcript_hash},
+	protocol::now_millis,
 };
-use bytes::BytesMut;
-use lib::{hash, hex};
 use rand_core::OsRng;
-use rsa::{PaddingScheme, PublicKeyParts, RsaPrivateKey};
-use std::error::Error;
-use tokio::{io::AsyncReadExt, net::TcpStream};
+use rsa::{pkcs8::ToPublicKey, PaddingScheme, PublicKeyParts, RsaPrivateKey};
+use std::{sync::Arc, time::Duration};
+use tokio::{
+	io::{AsyncReadExt, AsyncWriteExt},
+	net::{tcp::OwnedReadHalf, TcpStream},
+	time::timeout,
+};
 use x25519_dalek::{EphemeralSecret, PublicKey};
 
+// a client which hasn't finished the key exchange by then is dropped,
+// long enough for someone connecting for the first time to read & accept our key fingerprint
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/*
+Runs in its own task so a slow (or silent) client can't hold up the broker,
+the finished Client is handed back to the broker with Event::PeerReady,
+which starts reading from it once it is registered
+*/
 pub async fn new_peer(
-	inner_server: &mut InnerServer,
 	sender: Sender,
 	stream: TcpStream,
-	key: RsaPrivateKey,
+	key: Arc<RsaPrivateKey>,
+	id: String,
 	server_id: String,
-) -> Result<(), Box<dyn Error>> {
-	let (mut read, write) = stream.into_split();
-	// key exchange
+	limits: Limits,
+) {
+	let handshake = handshake(sender.clone(), stream, key, id.clone(), server_id, limits);
 
-	// generate our secret/public key
-	let secret = EphemeralSecret::new(OsRng);
-	let public = PublicKey::from(&secret);
+	let event = match timeout(HANDSHAKE_TIMEOUT, handshake).await {
+		Ok(Ok((client, read))) => Event::PeerReady(Box::new(client), read),
+		Ok(Err(e)) => {
+			eprintln!("handshake with {id} failed: {e}");
+			Event::HandshakeFailed
+		}
+		Err(_) => {
+			eprintln!("handshake with {id} timed out");
+			Event::HandshakeFailed
+		}
+	};
+	let _ = sender.send(event);
+}
 
-	// write our public key to the client
-	let _ = write.try_write(public.as_bytes());
+async fn handshake(
+	sender: Sender,
+	stream: TcpStream,
+	key: Arc<RsaPrivateKey>,
+	id: String,
+	server_id: String,
+	limits: Limits,
+) -> Result<(Client, OwnedReadHalf), lib::Error> {
+	let (mut read, mut write) = stream.into_split();
 
-	// write our id to the client
-	let _ = write.try_write(server_id.clone().as_bytes());
+	// present our public key first, the client pins it on first use (look at client/src/known_hosts.rs)
+	let public_key = key.to_public_key().to_public_key_der()?;
+	let public_key = public_key.as_ref();
+	write
+		.write_all(&(public_key.len() as u16).to_be_bytes())
+		.await?;
+	write.write_all(public_key).await?;
 
-	// get the encryption size based of our private key & read the decrypted public key into the public buffer
-	let size = key.size() + 64; // adding in their id
-	println!("size: {} + 64", key.size());
-
-	let mut buff = vec![0u8; size];
-	let _ = read.readable().await;
-	if size != read.read_exact(&mut buff).await? {
-		panic!(
-			"did not match full buffer size, got: {}, size: {}",
-			buff.len(),
-			size
-		);
-	}
+	// key exchange, the client goes first
 
-	println!("size of buffer is: {}", buff.len());
-	let encrypted_buf = &buff[0..key.size()];
-	let id_buf = &buff[key.size()..size];
-	println!("id_buf: {:?}", id_buf);
-	let id = String::from_utf8(id_buf.to_vec())?;
+	// get the encryption size based of our private key & read the decrypted public key into the public buffer
+	let mut encrypted_buf = vec![0u8; key.size()];
+	read.read_exact(&mut encrypted_buf).await?;
 
 	let padding = PaddingScheme::new_pkcs1v15_encrypt();
-	let their_public_input = match key.decrypt(padding, encrypted_buf) {
-		Ok(bytes) => bytes,
-		Err(e) => return Err(Box::new(e)),
-	};
+	let their_public_input = key.decrypt(padding, &encrypted_buf)?;
 
 	// change into a 32 byte array
-	let mut public_buffer = [0u8; 32];
-	(0usize..32usize)
-		.into_iter()
-		.for_each(|i| public_buffer[i] = their_public_input[i].clone());
+	let public_buffer: [u8; 32] = match their_public_input.try_into() {
+		Ok(public_buffer) => public_buffer,
+		Err(_) => {
+			return Err(lib::Error::Handshake(
+				"client DH public key is not 32 bytes".to_string(),
+			))
+		}
+	};
 
-	// get the shared secret!
-	let their_public = PublicKey::from(public_buffer);
-	let shared_secret = hash(secret.diffie_hellman(&their_public).as_bytes());
+	// generate our secret/public key
+	let secret = EphemeralSecret::new(OsRng);
+	let public = PublicKey::from(&secret);
 
-	#[cfg(debug_assertions)]
-	println!(
-		"the client's ({}) shared secret is: {}",
-		id.clone(),
-		hex(&shared_secret).as_str()
+	// sign everything exchanged so far so the client knows our public key really came from us
+	let transcript = transcript_hash(
+		&public_buffer,
+		id.as_bytes(),
+		public.as_bytes(),
+		server_id.as_bytes(),
 	);
+	let signature = sign_transcript(&key, &transcript)?;
 
-	let client = Client::new(id.clone(), write, &shared_secret, sender.clone(), server_id);
-	tokio::spawn(listen_client(id.clone(), sender, read));
-	inner_server.add_client(id.clone(), client);
+	// write our public key, id, their id & the signature to the client
+	let response = [
+		public.as_bytes(),
+		server_id.as_bytes(),
+		id.as_bytes(),
+		&signature[..],
+	]
+	.concat();
+	write.write_all(&response).await?;
 
-	Ok(())
+	// get the shared secret & a key for each direction out of it!
+	let their_public = PublicKey::from(public_buffer);
+	let keys = derive_session_keys(secret.diffie_hellman(&their_public).as_bytes(), &transcript);
+
+	let client = Client::new(id, write, keys, sender, server_id, &limits);
+	Ok((client, read))
 }
 
 pub async fn broker(mut receiver: Receiver, mut inner_server: InnerServer) {
@@ -86,25 +122,76 @@ pub async fn broker(mut receiver: Receiver, mut inner_server: InnerServer) {
 		match event {
 			Event::SetServerId(id) => inner_server.set_id(id),
 			Event::NewPeer(sender, stream, key) => {
+				if inner_server.is_full() {
+					// dropping the stream closes the connection before we spend any time on a key exchange
+					continue;
+				}
+
+				// we pick the id, a client choosing its own could take over someone else's session
+				let id = inner_server.new_session_id();
 				let server_id = inner_server.get_id().to_string();
-				let _ = new_peer(&mut inner_server, sender, stream, key, server_id.clone()).await;
+				let limits = inner_server.get_limits().clone();
+
+				inner_server.start_handshake();
+				tokio::spawn(new_peer(sender, stream, key, id, server_id, limits));
 			}
+			Event::PeerReady(mut client, read) => {
+				inner_server.finish_handshake();
+				client.listen(read, inner_server.get_limits().clone());
+				inner_server.add_client(client.get_id().to_string(), *client);
+			}
+			Event::HandshakeFailed => inner_server.finish_handshake(),
 			Event::RelayFeed(id, recepient_id, buf) => {
-				if &recepient_id == inner_server.get_id() {
+				if recepient_id == inner_server.get_id() {
 					inner_server.read_feed(&id, buf).await;
+				} else if recepient_id == id {
+					// frames are addressed to the server or another client, never to the sender
+					inner_server.disconnect_client(&id);
 				} else {
-					// future E2EE
-					panic!(
-						"not yet implemented, recepient id: {:?}, server id: {:?}",
-						recepient_id,
-						inner_server.get_id()
-					);
+					// E2EE between two clients, we can't (and shouldn't) read it
+					inner_server.relay_direct(&id, &recepient_id, buf);
 				}
 			}
 			Event::SendToAll(data) => inner_server.send_instructions_to_all(data),
 			Event::SendToOthers(sender_id, data) => {
 				inner_server.send_instructions_to_others(&sender_id, data)
 			}
+			Event::DisconnectClient(id) => inner_server.disconnect_client(&id),
+			Event::ClientDisconnected(id) => inner_server.connection_lost(&id),
+			Event::Resume(id, token) => {
+				inner_server.resume(&id, token);
+				inner_server.release_held(&id).await;
+			}
+			Event::ResumeExpired(id) => inner_server.expire_resume(&id),
+			Event::LogStats => inner_server.log_queue_stats(),
+			Event::Shutdown(reason, reconnect_after, drained) => {
+				let writers = inner_server.shutdown(reason, reconnect_after);
+				tokio::spawn(async move {
+					futures::future::join_all(writers).await;
+					let _ = drained.send(());
+				});
+				return;
+			}
+			Event::PingClient(id) => {
+				inner_server.send_instructions_to(&id, vec![Instruction::Ping(now_millis())])
+			}
+			Event::ListUsers(id) => inner_server.send_user_list(&id),
+			Event::ClaimNick(id, username) => {
+				inner_server.claim_nick(&id, username);
+				inner_server.release_held(&id).await;
+			}
+			Event::FetchHistory(id, before, limit) => inner_server.send_history(&id, before, limit),
+			Event::JoinChannel(id, channel) => inner_server.join_channel(&id, channel),
+			Event::PartChannel(id, channel) => inner_server.part_channel(&id, channel),
+			Event::SendToChannel(sender_id, channel, data) => {
+				inner_server.send_instructions_to_channel(&sender_id, &channel, data)
+			}
+			Event::DirectKeyOffer(sender_id, username, public_key) => {
+				inner_server.forward_key_offer(&sender_id, &username, public_key)
+			}
+			Event::DirectKeyAnswer(sender_id, peer_id, public_key) => {
+				inner_server.forward_key_answer(&sender_id, &peer_id, public_key)
+			}
 		}
 	}
 }
@@ -1,83 +1,328 @@
-use super::Sender;
-use crate::server::{feed::handle_feed, Event};
+use super::{
+	queue::{write_client, Outgoing, QueueStats, WriteQueue},
+	Sender,
+};
+use crate::{
+	config::Limits,
+	server::{feed::handle_feed, Event},
+};
+use futures::StreamExt;
 use lib::{
-	encoding::{Decoder, Encoder, Instruction},
-	encryption::{self, decrypt},
-	stream::{self, StreamOperation},
+	codec::FrameCodec,
+	encoding::{Decoder, Encoder, ErrorCode, Instruction},
+	encryption::{Opener, Session},
+	handshake::SessionKeys,
+	hex_hash,
+	protocol::{self, Capabilities},
 };
-use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use rand_core::{OsRng, RngCore};
+use tokio::{
+	net::tcp::{OwnedReadHalf, OwnedWriteHalf},
+	task::JoinHandle,
+	time::{self, Instant},
+};
+use tokio_util::codec::FramedRead;
+
+// instructions kept while a username claim is pending
+const MAX_HELD: usize = 64;
 
-pub async fn listen_client(id: String, sender: Sender, mut stream: OwnedReadHalf) {
+/*
+Any frame counts as a sign of life, a client that went quiet for a whole heartbeat interval gets pinged
+& one which stays quiet for missed_heartbeats intervals (a half open connection for example) is dropped
+*/
+pub async fn listen_client(id: String, sender: Sender, stream: OwnedReadHalf, limits: Limits) {
 	#[cfg(debug_assertions)]
 	println!("listening to client {id}");
 
+	let mut frames = FramedRead::new(stream, FrameCodec::new(limits.max_frame_size));
+	let interval = limits.heartbeat_interval();
+	let mut heartbeat = time::interval_at(Instant::now() + interval, interval);
+	let mut last_seen = Instant::now();
+
 	loop {
-		let (recepient_id, encrypted_buf) = match stream::read_stream(&mut stream).await {
-			Ok((r, e)) => (r, e),
-			Err(StreamOperation::Continue) => continue,
-			Err(StreamOperation::Break) => break,
-		};
+		tokio::select! {
+			frame = frames.next() => {
+				// once framing breaks there is no way to find the start of the next frame again
+				let frame = match frame {
+					Some(Ok(frame)) => frame,
+					Some(Err(e)) => {
+						#[cfg(debug_assertions)]
+						eprintln!("error while reading frame from client {id}: {e}");
 
-		let _ = sender.send(Event::RelayFeed(
-			id.clone(),
-			recepient_id,
-			encrypted_buf.to_vec(),
-		));
+						break;
+					}
+					None => break,
+				};
+
+				last_seen = Instant::now();
+				let _ = sender.send(Event::RelayFeed(id.clone(), frame.id, frame.data));
+			}
+			_ = heartbeat.tick() => {
+				if last_seen.elapsed() >= limits.heartbeat_timeout() {
+					#[cfg(debug_assertions)]
+					eprintln!("client {id} missed {} heartbeats", limits.missed_heartbeats);
+
+					break;
+				}
+				if last_seen.elapsed() >= interval {
+					let _ = sender.send(Event::PingClient(id.clone()));
+				}
+			}
+		}
 	}
 
-	// todo: remove client from server's client tables to cleanup
+	let _ = sender.send(Event::ClientDisconnected(id));
 }
 
 pub struct Client {
 	#[allow(dead_code)]
 	id: String,
 	server_id: String,
-	write: OwnedWriteHalf,
-	shared_secret: Vec<u8>,
+	reader: Option<JoinHandle<()>>, // started by the broker once the client is registered, look at listen
+	writer: Option<JoinHandle<()>>, // only taken by close
+	queue: WriteQueue,              // frames for the writer task, look at server/src/server/queue.rs
+	opener: Opener, // reading half of the session, the writer task seals with the other half
 	#[allow(dead_code)]
 	sender: Sender,
+	protocol_version: Option<u16>, // set once the hello exchange succeeded
+	capabilities: Capabilities,
+	heartbeat: (u64, u32), // interval & missed heartbeats, the client uses the same ones
+	instantiated: bool,    // whether we got a username yet
+	claiming: bool,        // a username (or resume) is waiting on the broker, look at hold
+	held: Vec<Instruction>, // what came in while claiming, handled once the broker decided
+	resume_token: Option<String>, // given out with the username, look at InnerServer::resume
 	pub username: String,
 }
 
+impl std::fmt::Debug for Client {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("Client")
+			.field("id", &self.id)
+			.field("username", &self.username)
+			.finish()
+	}
+}
+
 impl Client {
 	pub fn new(
 		id: String,
 		write: OwnedWriteHalf,
-		shared_secret: &[u8],
+		keys: SessionKeys,
 		sender: Sender,
 		server_id: String,
+		limits: &Limits,
 	) -> Self {
+		let (sealer, opener) = Session::new(
+			keys.server_to_client,
+			keys.client_to_server,
+			server_id.clone(),
+			id.clone(),
+		)
+		.split();
+
+		let queue = WriteQueue::new(limits.write_queue, limits.max_dropped);
+		let writer = tokio::spawn(write_client(
+			id.clone(),
+			queue.clone(),
+			write,
+			sealer,
+			sender.clone(),
+		));
+
 		Self {
 			id,
 			server_id,
-			write,
-			shared_secret: shared_secret.to_vec(),
+			reader: None,
+			writer: Some(writer),
+			queue,
+			opener,
 			sender,
+			protocol_version: None,
+			capabilities: Capabilities::empty(),
+			heartbeat: (limits.heartbeat_interval, limits.missed_heartbeats),
+			instantiated: false,
+			claiming: false,
+			held: Vec::new(),
+			resume_token: None,
 			username: "Unknown".to_string(),
 		}
 	}
 
-	// size hint (64 bits) | sender (512 bits) | encrypted buffer
-	pub fn make_payload(&self, sender: &str, buff: &[u8]) -> Vec<u8> {
-		let sender = sender.as_bytes().to_vec();
-		let encrypted_buf = encryption::encrypt(&self.shared_secret, buff);
-		let len = encrypted_buf.len() as u64;
-		[len.to_be_bytes().to_vec(), sender, encrypted_buf].concat()
+	pub fn is_negotiated(&self) -> bool {
+		self.protocol_version.is_some()
+	}
+
+	pub fn is_instantiated(&self) -> bool {
+		self.instantiated
+	}
+
+	// only the broker can tell whether a username is taken, so it gets the final say
+	pub fn request_nick(&mut self, username: String) {
+		if !protocol::is_valid_username(&username) {
+			return self.send_local_instructions(vec![Instruction::Error(
+				ErrorCode::InvalidUsername,
+				format!("{} is not a valid username", username),
+			)]);
+		}
+
+		self.claiming = !self.instantiated;
+		let _ = self
+			.sender
+			.send(Event::ClaimNick(self.id.clone(), username));
+	}
+
+	pub fn is_claiming(&self) -> bool {
+		self.claiming
+	}
+
+	// a message sent right behind the username shouldn't be turned away just because the claim isn't through yet
+	pub fn hold(&mut self, instr: Instruction) {
+		if self.held.len() >= MAX_HELD {
+			return self.send_local_instructions(vec![Instruction::Error(
+				ErrorCode::UsernameRequired,
+				"wait for your username to be accepted".to_string(),
+			)]);
+		}
+		self.held.push(instr);
+	}
+
+	// the broker decided on the claim, whatever was held is handled now that we know the outcome
+	pub async fn release_held(&mut self) {
+		self.claiming = false;
+		let held = std::mem::take(&mut self.held);
+		if !held.is_empty() {
+			handle_feed(self, held).await;
+		}
+	}
+
+	pub fn instantiate(&mut self, username: String) {
+		self.username = username;
+		self.instantiated = true;
+		self.issue_resume_token();
+
+		self.send_to_others(vec![
+			Instruction::ReceiveMessage(
+				"Server".to_string(),
+				format!("{} joined the chat", self.username),
+			),
+			Instruction::UserJoined(self.username.clone()),
+		]);
+		self.list_users();
+		self.send_local_message(format!("Hi {}", self.username));
+	}
+
+	// the connection this client had before was lost, it takes over that username quietly
+	pub fn resume_as(&mut self, username: String) {
+		self.username = username;
+		self.instantiated = true;
+		self.issue_resume_token();
+		self.send_local_message(format!("Welcome back {}", self.username));
+	}
+
+	pub fn request_resume(&mut self, token: String) {
+		self.claiming = true;
+		let _ = self.sender.send(Event::Resume(self.id.clone(), token));
+	}
+
+	// a token is only good once, every resume hands out a new one
+	fn issue_resume_token(&mut self) {
+		let mut bytes = [0u8; 32];
+		OsRng.fill_bytes(&mut bytes);
+
+		let token = hex_hash(&bytes);
+		self.resume_token = Some(token.clone());
+		self.send_local_instructions(vec![Instruction::ResumeToken(token)]);
+	}
+
+	pub fn resume_token(&self) -> Option<&str> {
+		self.resume_token.as_deref()
+	}
+
+	pub fn sender(&self) -> Sender {
+		self.sender.clone()
+	}
+
+	pub fn fetch_history(&mut self, before: u64, limit: usize) {
+		let _ = self
+			.sender
+			.send(Event::FetchHistory(self.id.clone(), before, limit));
+	}
+
+	pub fn list_users(&mut self) {
+		let _ = self.sender.send(Event::ListUsers(self.id.clone()));
+	}
+
+	pub fn hello(&mut self, version: u16, capabilities: Capabilities) {
+		match protocol::negotiate(version, capabilities, super::SUPPORTED_CAPABILITIES) {
+			Ok((version, capabilities)) => {
+				#[cfg(debug_assertions)]
+				println!(
+					"client {} negotiated protocol version {} with capabilities {:?}",
+					self.id, version, capabilities
+				);
+
+				self.protocol_version = Some(version);
+				self.capabilities = capabilities;
+				let (interval, missed) = self.heartbeat;
+				self.send_local_instructions(vec![
+					Instruction::Hello(version, capabilities.bits()),
+					Instruction::Heartbeat {
+						interval,
+						missed: missed as u64,
+					},
+				]);
+			}
+			Err(e) => self.reject(ErrorCode::IncompatibleVersion, e.to_string()),
+		}
+	}
+
+	// tells the client why it is being dropped and asks the broker to drop it
+	pub fn reject(&mut self, code: ErrorCode, reason: String) {
+		self.send_local_instructions(vec![Instruction::Error(code, reason)]);
+		let _ = self.sender.send(Event::DisconnectClient(self.id.clone()));
+	}
+
+	// the client moved its sending key forward, we follow along for reading
+	pub fn rekey(&mut self, generation: u64) {
+		if let Err(e) = self.opener.rekey(generation) {
+			self.reject(ErrorCode::InvalidRekey, e.to_string());
+		}
+	}
+
+	// droppable frames may be thrown away when the client falls behind, look at queue.rs -> WriteQueue
+	pub fn make_and_send(&mut self, sender: &str, buff: &[u8], droppable: bool) {
+		self.queue_frame(Outgoing::Local {
+			sender: sender.to_string(),
+			data: buff.to_vec(),
+			droppable,
+		})
+	}
+
+	// frames between two clients are end to end encrypted, so they are passed along untouched
+	pub fn send_opaque(&mut self, sender: &str, buff: Vec<u8>) {
+		self.queue_frame(Outgoing::Opaque {
+			sender: sender.to_string(),
+			buffer: buff,
+		})
 	}
 
-	pub fn make_and_send(&mut self, sender: &str, buff: &[u8]) {
-		let payload = self.make_payload(sender, buff);
-		match self.write.try_write(&payload) {
-			Ok(_) => {}
-			Err(e) => eprintln!("{:?}", e.to_string()),
+	fn queue_frame(&mut self, frame: Outgoing) {
+		if let Err(e) = self.queue.push(frame) {
+			eprintln!("disconnecting client {}: {}", self.id, e);
+			let _ = self.sender.send(Event::DisconnectClient(self.id.clone()));
 		}
 	}
 
-	pub async fn read_feed(&mut self, buff: Vec<u8>) {
-		let decrypted_buff = decrypt(&self.shared_secret, buff);
-		let decoder = Decoder::from_bytes(decrypted_buff);
+	pub fn queue_stats(&self) -> QueueStats {
+		self.queue.stats()
+	}
+
+	pub async fn read_feed(&mut self, buff: Vec<u8>) -> Result<(), lib::Error> {
+		let decrypted_buff = self.opener.open(&buff)?;
+		let decoder = Decoder::from_bytes(decrypted_buff)?;
 
 		handle_feed(self, decoder.feed).await;
+		Ok(())
 	}
 
 	pub fn send_to_all(&mut self, feed: Vec<Instruction>) {
@@ -88,7 +333,90 @@ impl Client {
 		let _ = self.sender.send(Event::SendToOthers(self.id.clone(), feed));
 	}
 
+	// false (after telling the client why) when channels weren't negotiated or the name is invalid
+	fn check_channel(&mut self, channel: &str) -> bool {
+		if !self.capabilities.contains(Capabilities::CHANNELS) {
+			self.send_local_instructions(vec![Instruction::Error(
+				ErrorCode::UnsupportedFeature,
+				"channels were not negotiated for this session".to_string(),
+			)]);
+			return false;
+		}
+
+		if !protocol::is_valid_channel_name(channel) {
+			self.send_local_instructions(vec![Instruction::Error(
+				ErrorCode::InvalidChannel,
+				format!("{} is not a valid channel name", channel),
+			)]);
+			return false;
+		}
+
+		true
+	}
+
+	pub fn join_channel(&mut self, channel: String) {
+		if self.check_channel(&channel) {
+			let _ = self
+				.sender
+				.send(Event::JoinChannel(self.id.clone(), channel));
+		}
+	}
+
+	pub fn part_channel(&mut self, channel: String) {
+		if self.check_channel(&channel) {
+			let _ = self
+				.sender
+				.send(Event::PartChannel(self.id.clone(), channel));
+		}
+	}
+
+	pub fn send_channel_message(&mut self, channel: String, content: String) {
+		if self.check_channel(&channel) && self.check_message(&content) {
+			let feed = vec![Instruction::ChannelMessage(
+				channel.clone(),
+				self.username.clone(),
+				content,
+			)];
+			let _ = self
+				.sender
+				.send(Event::SendToChannel(self.id.clone(), channel, feed));
+		}
+	}
+
+	// the server only passes public keys along, the shared secret is only known by the two clients
+	pub fn offer_direct_key(&mut self, username: String, public_key: Vec<u8>) {
+		let _ = self
+			.sender
+			.send(Event::DirectKeyOffer(self.id.clone(), username, public_key));
+	}
+
+	pub fn answer_direct_key(&mut self, peer_id: String, public_key: Vec<u8>) {
+		let _ = self
+			.sender
+			.send(Event::DirectKeyAnswer(self.id.clone(), peer_id, public_key));
+	}
+
+	// false (after telling the client why) when the message is too long to be sent on
+	fn check_message(&mut self, content: &str) -> bool {
+		if content.len() > protocol::MAX_MESSAGE_LENGTH {
+			self.send_local_instructions(vec![Instruction::Error(
+				ErrorCode::MessageTooLong,
+				format!(
+					"messages can be at most {} bytes",
+					protocol::MAX_MESSAGE_LENGTH
+				),
+			)]);
+			return false;
+		}
+
+		true
+	}
+
 	pub fn send_message(&mut self, content: String) {
+		if !self.check_message(&content) {
+			return;
+		}
+
 		self.send_to_all(vec![Instruction::ReceiveMessage(
 			self.username.clone(),
 			content,
@@ -98,7 +426,7 @@ impl Client {
 	pub fn send_local_instructions(&mut self, feed: Vec<Instruction>) {
 		let data = Encoder::from_feed(feed).writer.dump();
 		let id = self.server_id.clone();
-		self.make_and_send(&id, &data);
+		self.make_and_send(&id, &data, false);
 	}
 
 	pub fn send_local_message(&mut self, content: String) {
@@ -112,3 +440,30 @@ impl Client {
 		&self.id
 	}
 }
+
+impl Client {
+	// starts reading frames, only once the broker knows the client so none of its events arrive before it
+	pub fn listen(&mut self, read: OwnedReadHalf, limits: Limits) {
+		self.reader = Some(tokio::spawn(listen_client(
+			self.id.clone(),
+			self.sender.clone(),
+			read,
+			limits,
+		)));
+	}
+
+	// lets go of the client, the writer still sends what is queued & finishes the returned handle once it is done
+	pub fn close(mut self) -> Option<JoinHandle<()>> {
+		self.writer.take()
+	}
+}
+
+impl Drop for Client {
+	fn drop(&mut self) {
+		// the writer gets to flush what is already queued (like the reason for a reject), then closes the socket
+		self.queue.close();
+		if let Some(reader) = &self.reader {
+			reader.abort();
+		}
+	}
+}
@@ -1,18 +1,47 @@
 use super::client::Client;
-use lib::encoding::Instruction::{self, *};
+use lib::{
+	encoding::{
+		ErrorCode,
+		Instruction::{self, *},
+	},
+	protocol::Capabilities,
+};
 
 pub async fn handle_feed(client: &mut Client, feed: Vec<Instruction>) {
 	for instr in feed {
 		match instr {
-			Instantiate(username) => {
-				client.username = username;
-
-				use tokio::time::{sleep, Duration};
-				sleep(Duration::from_millis(1000)).await;
-				println!("sending message!");
-				client.send_local_message(format!("Hi {}", client.username));
+			Rekey(generation) => client.rekey(generation),
+			Ping(sent) => client.send_local_instructions(vec![Pong(sent)]),
+			Pong(_) => {} // listen_client already saw the frame, that's all a pong is for
+			Hello(version, capabilities) => {
+				client.hello(version, Capabilities::from_bits(capabilities))
+			}
+			_ if !client.is_negotiated() => {
+				return client.reject(
+					ErrorCode::HelloRequired,
+					"a hello with the protocol version must be sent first".to_string(),
+				)
 			}
+			// kept in order, a second claim waits for the first one
+			instr if client.is_claiming() => client.hold(instr),
+			Instantiate(username) | ChangeNick(username) => client.request_nick(username),
+			Resume(token) if !client.is_instantiated() => client.request_resume(token),
+			ListUsers => client.list_users(),
+			_ if !client.is_instantiated() => client.send_local_instructions(vec![Error(
+				ErrorCode::UsernameRequired,
+				"pick a username first".to_string(),
+			)]),
 			SendMessage(content) => client.send_message(content),
+			FetchHistory { before, limit } => client.fetch_history(before, limit as usize),
+			JoinChannel(channel) => client.join_channel(channel),
+			PartChannel(channel) => client.part_channel(channel),
+			ChannelMessage(channel, _, content) => client.send_channel_message(channel, content),
+			DirectKeyOffer(_, username, public_key) => {
+				client.offer_direct_key(username, public_key)
+			}
+			DirectKeyAnswer(peer_id, _, public_key) => {
+				client.answer_direct_key(peer_id, public_key)
+			}
 			_ => {}
 		}
 	}
@@ -1,34 +1,121 @@
-use std::collections::HashMap;
+use std::{
+	collections::{HashMap, HashSet},
+	sync::Arc,
+	time::{Duration, Instant},
+};
 
-use lib::encoding::{Encoder, Instruction};
+use lib::{
+	encoding::{Encoder, ErrorCode, Instruction},
+	hex_hash,
+	protocol::{self, Capabilities},
+};
+use rand_core::{OsRng, RngCore};
 use rsa::RsaPrivateKey;
-use tokio::{net::TcpStream, sync::mpsc};
+use tokio::{
+	net::{tcp::OwnedReadHalf, TcpStream},
+	sync::{mpsc, oneshot},
+	task::JoinHandle,
+};
 
 use self::client::Client;
+use crate::{
+	config::{Config, Limits},
+	history::HistoryStore,
+};
 mod broker;
 mod client;
 mod feed;
+mod queue;
 
 #[derive(Debug)]
 pub enum Event {
 	SetServerId(String),
-	NewPeer(Sender, TcpStream, RsaPrivateKey),
+	NewPeer(Sender, TcpStream, Arc<RsaPrivateKey>),
+	PeerReady(Box<Client>, OwnedReadHalf), // key exchange done, ready to be added & read from
+	HandshakeFailed,
 	RelayFeed(String, String, Vec<u8>), // ClientId, RecepientId, Encrypted Data
 	SendToAll(Vec<Instruction>),
 	SendToOthers(String, Vec<Instruction>),
+	DisconnectClient(String),   // ClientId
+	ClientDisconnected(String), // ClientId
+	PingClient(String),         // ClientId, it has been quiet for a while
+	Resume(String, String),     // ClientId, Resume Token
+	ResumeExpired(String),      // ClientId of the lost connection
+	LogStats,
+	Shutdown(String, u64, oneshot::Sender<()>), // Reason, Reconnect After (seconds), answered once every client is flushed
+	ListUsers(String),                          // ClientId
+	ClaimNick(String, String),                  // ClientId, Username
+	FetchHistory(String, u64, usize),           // ClientId, Before, Limit
+	JoinChannel(String, String),                // ClientId, Channel
+	PartChannel(String, String),                // ClientId, Channel
+	SendToChannel(String, String, Vec<Instruction>), // SenderId, Channel, Feed
+	DirectKeyOffer(String, String, Vec<u8>),    // SenderId, Target Username, Public Key
+	DirectKeyAnswer(String, String, Vec<u8>),   // SenderId, PeerId, Public Key
 }
 pub type Sender = mpsc::UnboundedSender<Event>;
 pub type Receiver = mpsc::UnboundedReceiver<Event>;
 
+// optional protocol features this server implements, see lib/src/protocol.rs
+pub const SUPPORTED_CAPABILITIES: Capabilities = Capabilities::CHANNELS;
+
+// broadcasts kept for a client that lost its connection, the oldest go first
+const MAX_MISSED: usize = 256;
+
+/*
+A client whose connection dropped, waiting for it to come back with its resume token,
+it stays in its channels & keeps its username until resume_grace runs out
+*/
+struct Parked {
+	token: String,
+	username: String,
+	missed: Vec<Vec<u8>>, // encoded feeds it would have gotten
+	expires: Instant,     // when resume_grace runs out & the username is free again
+}
+
+impl Parked {
+	fn miss(&mut self, buff: &[u8]) {
+		if self.missed.len() >= MAX_MISSED {
+			self.missed.remove(0);
+		}
+		self.missed.push(buff.to_vec());
+	}
+}
+
+// only chat may be dropped for a client which falls behind, everything else changes what it knows
+fn is_chat(feed: &[Instruction]) -> bool {
+	feed.iter().all(|instr| {
+		matches!(
+			instr,
+			Instruction::ReceiveMessage(..) | Instruction::ChannelMessage(..)
+		)
+	})
+}
+
+// asks the broker for queue stats every so often, until it is gone
+async fn log_stats(sender: Sender, interval: Duration) {
+	let mut ticker = tokio::time::interval(interval);
+	ticker.tick().await; // the first one is right away, nobody is connected yet
+	loop {
+		ticker.tick().await;
+		if sender.send(Event::LogStats).is_err() {
+			return;
+		}
+	}
+}
+
 /*
 Handles all the exterior functionality & event controlling,
 used by a Socket
 */
+#[derive(Clone)]
 pub struct OuterServer(Sender);
 
 impl OuterServer {
 	pub fn new(inner_server: InnerServer) -> Self {
 		let (sender, receiver) = mpsc::unbounded_channel::<Event>();
+		if let Some(interval) = inner_server.get_limits().stats_interval() {
+			tokio::spawn(log_stats(sender.clone(), interval));
+		}
 		let _handle = tokio::spawn(broker::broker(receiver, inner_server));
 
 		Self(sender)
@@ -49,17 +136,70 @@ used by the broker
 */
 pub struct InnerServer {
 	clients: HashMap<String, client::Client>,
+	parked: HashMap<String, Parked>, // client id of the lost connection -> what it left behind
+	channels: HashMap<String, HashSet<String>>, // channel name -> member client ids
+	history: Box<dyn HistoryStore>,
+	limits: Limits,
+	motd: Option<String>,
+	handshakes: usize, // key exchanges still running, they count towards max_clients
 	id: String,
 }
 
 impl InnerServer {
-	pub fn new() -> Self {
+	pub fn new(config: &Config, history: Box<dyn HistoryStore>) -> Self {
 		Self {
 			clients: HashMap::new(),
+			parked: HashMap::new(),
+			channels: HashMap::new(),
+			history,
+			limits: config.limits.clone(),
+			motd: config.motd.clone(),
+			handshakes: 0,
 			id: String::from(""),
 		}
 	}
 
+	pub fn get_limits(&self) -> &Limits {
+		&self.limits
+	}
+
+	pub fn is_full(&self) -> bool {
+		self.clients.len() + self.handshakes >= self.limits.max_clients
+	}
+
+	pub fn start_handshake(&mut self) {
+		self.handshakes += 1;
+	}
+
+	pub fn finish_handshake(&mut self) {
+		self.handshakes = self.handshakes.saturating_sub(1);
+	}
+
+	// what someone sees right after picking their first username
+	fn welcome(&mut self, id: &str) {
+		self.send_history(id, 0, self.limits.history_on_join);
+
+		if let Some(motd) = self.motd.clone() {
+			self.send_instructions_to(
+				id,
+				vec![Instruction::ReceiveMessage("Server".to_string(), motd)],
+			);
+		}
+	}
+
+	// 32 random bytes, hashed into the same 64 character hex form as every other id
+	pub fn new_session_id(&self) -> String {
+		loop {
+			let mut bytes = [0u8; 32];
+			OsRng.fill_bytes(&mut bytes);
+
+			let id = hex_hash(&bytes);
+			if !self.clients.contains_key(&id) && !self.parked.contains_key(&id) && id != self.id {
+				return id;
+			}
+		}
+	}
+
 	pub fn add_client(&mut self, id: String, client: Client) {
 		#[cfg(debug_assertions)]
 		println!("adding new client with id: {id}");
@@ -67,36 +207,477 @@ impl InnerServer {
 		self.clients.insert(id.clone(), client);
 	}
 
+	pub fn remove_client(&mut self, id: &str) -> Option<Client> {
+		for members in self.channels.values_mut() {
+			members.remove(id);
+		}
+		self.channels.retain(|_, members| !members.is_empty());
+
+		let client = self.clients.remove(id)?;
+
+		#[cfg(debug_assertions)]
+		{
+			let stats = client.queue_stats();
+			println!(
+				"removing client with id: {id} (wrote {} frames, peak queue depth {}, dropped {})",
+				stats.written, stats.peak, stats.dropped
+			);
+		}
+
+		Some(client)
+	}
+
+	// a line for the log, how far behind the clients are on what we send them
+	pub fn log_queue_stats(&self) {
+		if self.clients.is_empty() {
+			return;
+		}
+
+		let (mut waiting, mut deepest, mut peak, mut dropped) = (0, 0, 0, 0);
+		for client in self.clients.values() {
+			let stats = client.queue_stats();
+			waiting += stats.depth;
+			deepest = deepest.max(stats.depth);
+			peak = peak.max(stats.peak);
+			dropped += stats.dropped;
+		}
+		println!(
+			"write queues: {} clients, {waiting} frames waiting (deepest {deepest}), peak {peak}, {dropped} dropped",
+			self.clients.len()
+		);
+	}
+
+	// removes the client & lets everyone who could see them know they left
+	pub fn disconnect_client(&mut self, id: &str) {
+		// we wanted it gone, so it doesn't get to resume either
+		if self.parked.contains_key(id) {
+			return self.expire_resume(id);
+		}
+
+		self.part_all_channels(id);
+
+		let client = match self.remove_client(id) {
+			Some(client) => client,
+			None => return,
+		};
+
+		if client.is_instantiated() {
+			self.announce_left(&client.username);
+		}
+	}
+
+	fn part_all_channels(&mut self, id: &str) {
+		let channels: Vec<String> = self
+			.channels
+			.iter()
+			.filter(|(_, members)| members.contains(id))
+			.map(|(channel, _)| channel.clone())
+			.collect();
+		for channel in channels {
+			self.part_channel(id, channel);
+		}
+	}
+
+	fn announce_left(&mut self, username: &str) {
+		self.send_instructions_to_all(vec![
+			Instruction::ReceiveMessage(
+				"Server".to_string(),
+				format!("{} left the chat", username),
+			),
+			Instruction::UserLeft(username.to_string()),
+		]);
+	}
+
+	/*
+	Tells everyone we are going away, saves what has to be saved & lets go of every client,
+	the writers finish what is queued in the background, the returned handles finish with them
+	*/
+	pub fn shutdown(&mut self, reason: String, reconnect_after: u64) -> Vec<JoinHandle<()>> {
+		self.send_instructions_to_all(vec![Instruction::ServerShutdown {
+			reason,
+			reconnect_after,
+		}]);
+
+		if let Err(e) = self.history.flush() {
+			eprintln!("unable to flush history: {}", e);
+		}
+
+		self.parked.clear();
+		self.channels.clear();
+		self.clients
+			.drain()
+			.filter_map(|(_, client)| client.close())
+			.collect()
+	}
+
+	// the connection went away on its own, a client with a username gets some time to resume before it is let go
+	pub fn connection_lost(&mut self, id: &str) {
+		// the writer of a parked (or already removed) client noticing the socket is gone
+		if !self.clients.contains_key(id) {
+			return;
+		}
+
+		let has_token = self
+			.clients
+			.get(id)
+			.is_some_and(|client| client.resume_token().is_some());
+		if !has_token {
+			return self.disconnect_client(id);
+		}
+
+		if let Some(client) = self.clients.remove(id) {
+			self.park(client);
+		}
+	}
+
+	// dropping the client closes its socket, the id stays in its channels until it resumes or expires
+	fn park(&mut self, client: Client) {
+		let id = client.get_id().to_string();
+		let token = match client.resume_token() {
+			Some(token) => token.to_string(),
+			None => return,
+		};
+
+		#[cfg(debug_assertions)]
+		println!(
+			"parking client with id: {id} for {:?}",
+			self.limits.resume_grace()
+		);
+
+		let sender = client.sender();
+		let grace = self.limits.resume_grace();
+		let expired = id.clone();
+		tokio::spawn(async move {
+			tokio::time::sleep(grace).await;
+			let _ = sender.send(Event::ResumeExpired(expired));
+		});
+
+		self.parked.insert(
+			id,
+			Parked {
+				token,
+				username: client.username.clone(),
+				missed: Vec::new(),
+				expires: Instant::now() + grace,
+			},
+		);
+	}
+
+	// nobody came back for it, so now everyone is told it left
+	pub fn expire_resume(&mut self, id: &str) {
+		if !self.parked.contains_key(id) {
+			return;
+		}
+
+		self.part_all_channels(id);
+		if let Some(parked) = self.parked.remove(id) {
+			self.announce_left(&parked.username);
+		}
+	}
+
+	// a new connection taking over a lost one, it gets the username, channels & whatever it missed
+	pub fn resume(&mut self, id: &str, token: String) {
+		// the old connection might not have noticed it is gone yet
+		let stale = self
+			.clients
+			.iter()
+			.find(|(other, client)| other.as_str() != id && client.resume_token() == Some(&token))
+			.map(|(other, _)| other.clone());
+		if let Some(client) = stale.and_then(|stale| self.clients.remove(&stale)) {
+			self.park(client);
+		}
+
+		let old_id = match self.parked.iter().find(|(_, parked)| parked.token == token) {
+			Some((old_id, _)) => old_id.clone(),
+			None => {
+				return self.send_instructions_to(
+					id,
+					vec![Instruction::Error(
+						ErrorCode::InvalidResume,
+						"the session can't be resumed anymore".to_string(),
+					)],
+				)
+			}
+		};
+		let parked = match self.parked.remove(&old_id) {
+			Some(parked) => parked,
+			None => return,
+		};
+
+		for members in self.channels.values_mut() {
+			if members.remove(&old_id) {
+				members.insert(id.to_string());
+			}
+		}
+
+		let client = match self.clients.get_mut(id) {
+			Some(client) => client,
+			None => return,
+		};
+		client.resume_as(parked.username);
+		for buff in parked.missed {
+			client.make_and_send(&self.id, &buff, false);
+		}
+		self.send_user_list(id);
+	}
+
+	// parked clients count as online, nobody has been told they left
+	pub fn get_usernames(&self) -> Vec<String> {
+		let mut usernames: Vec<String> = self
+			.clients
+			.values()
+			.filter(|client| client.is_instantiated())
+			.map(|client| client.username.clone())
+			.chain(self.parked.values().map(|parked| parked.username.clone()))
+			.collect();
+		usernames.sort();
+		usernames
+	}
+
+	pub fn send_user_list(&mut self, id: &str) {
+		let usernames = self.get_usernames();
+		self.send_instructions_to(id, vec![Instruction::UserList(usernames)])
+	}
+
+	pub fn get_username(&self, id: &str) -> Option<&str> {
+		match self.clients.get(id) {
+			Some(client) => Some(client.username.as_str()),
+			None => self.parked.get(id).map(|parked| parked.username.as_str()),
+		}
+	}
+
+	pub fn find_client_by_username(&self, username: &str) -> Option<String> {
+		self.clients
+			.iter()
+			.find(|(_, client)| {
+				client.is_instantiated() && protocol::same_username(&client.username, username)
+			})
+			.map(|(id, _)| id.clone())
+	}
+
+	// gives a client its first username or renames it, as long as nobody else has it
+	pub fn claim_nick(&mut self, id: &str, username: String) {
+		// most likely the same user whose resume failed, so they are told how long until it is theirs again
+		let parked = self
+			.parked
+			.values()
+			.find(|parked| protocol::same_username(&parked.username, &username));
+		if let Some(parked) = parked {
+			let left = parked.expires.saturating_duration_since(Instant::now());
+			return self.send_instructions_to(
+				id,
+				vec![Instruction::Error(
+					ErrorCode::NicknameTaken,
+					format!(
+						"{} is kept for a connection which was lost, it is free again in {} seconds",
+						username,
+						left.as_secs() + 1
+					),
+				)],
+			);
+		}
+
+		if let Some(owner) = self.find_client_by_username(&username) {
+			if owner != id {
+				return self.send_instructions_to(
+					id,
+					vec![Instruction::Error(
+						ErrorCode::NicknameTaken,
+						format!("{} is already taken", username),
+					)],
+				);
+			}
+		}
+
+		let client = match self.clients.get_mut(id) {
+			Some(client) => client,
+			None => return,
+		};
+
+		if !client.is_instantiated() {
+			client.instantiate(username);
+			return self.welcome(id);
+		}
+		if client.username == username {
+			return;
+		}
+
+		let old_username = std::mem::replace(&mut client.username, username.clone());
+		self.send_instructions_to_all(vec![Instruction::NickChanged(old_username, username)]);
+	}
+
+	pub fn forward_key_offer(&mut self, sender_id: &str, username: &str, public_key: Vec<u8>) {
+		let target_id = match self.find_client_by_username(username) {
+			Some(target_id) => target_id,
+			None => {
+				return self.send_instructions_to(
+					sender_id,
+					vec![Instruction::DirectKeyRejected {
+						username: username.to_string(),
+					}],
+				);
+			}
+		};
+
+		let sender_username = self.get_username(sender_id).unwrap_or_default().to_string();
+		self.send_instructions_to(
+			&target_id,
+			vec![Instruction::DirectKeyOffer(
+				sender_id.to_string(),
+				sender_username,
+				public_key,
+			)],
+		)
+	}
+
+	pub fn forward_key_answer(&mut self, sender_id: &str, peer_id: &str, public_key: Vec<u8>) {
+		if !self.clients.contains_key(peer_id) {
+			return self.send_unknown_recepient(sender_id, peer_id);
+		}
+
+		let sender_username = self.get_username(sender_id).unwrap_or_default().to_string();
+		self.send_instructions_to(
+			peer_id,
+			vec![Instruction::DirectKeyAnswer(
+				sender_id.to_string(),
+				sender_username,
+				public_key,
+			)],
+		)
+	}
+
+	// relays an end to end encrypted frame, the recepient sees who it came from via the frame id
+	pub fn relay_direct(&mut self, sender_id: &str, recepient_id: &str, buff: Vec<u8>) {
+		match self.clients.get_mut(recepient_id) {
+			Some(client) => client.send_opaque(sender_id, buff),
+			None => self.send_unknown_recepient(sender_id, recepient_id),
+		}
+	}
+
+	fn send_unknown_recepient(&mut self, id: &str, recepient_id: &str) {
+		self.send_instructions_to(
+			id,
+			vec![Instruction::PeerGone {
+				id: recepient_id.to_string(),
+			}],
+		)
+	}
+
+	pub fn join_channel(&mut self, id: &str, channel: String) {
+		let username = match self.get_username(id) {
+			Some(username) => username.to_string(),
+			None => return,
+		};
+
+		let members = self.channels.entry(channel.clone()).or_default();
+		if !members.insert(id.to_string()) {
+			return;
+		}
+
+		// everyone in the channel, including the one joining, is told about it
+		self.send_instructions_to_channel_members(
+			&channel,
+			vec![Instruction::ChannelJoined(channel.clone(), username)],
+		);
+	}
+
+	pub fn part_channel(&mut self, id: &str, channel: String) {
+		if !self.is_in_channel(id, &channel) {
+			return self.send_not_in_channel(id, &channel);
+		}
+
+		let username = self.get_username(id).unwrap_or_default().to_string();
+		// notify before removing so the one leaving gets the notice as well
+		self.send_instructions_to_channel_members(
+			&channel,
+			vec![Instruction::ChannelParted(channel.clone(), username)],
+		);
+
+		if let Some(members) = self.channels.get_mut(&channel) {
+			members.remove(id);
+			if members.is_empty() {
+				self.channels.remove(&channel);
+			}
+		}
+	}
+
+	pub fn is_in_channel(&self, id: &str, channel: &str) -> bool {
+		self.channels
+			.get(channel)
+			.is_some_and(|members| members.contains(id))
+	}
+
+	fn send_not_in_channel(&mut self, id: &str, channel: &str) {
+		self.send_instructions_to(
+			id,
+			vec![Instruction::Error(
+				ErrorCode::NotInChannel,
+				format!("you are not in {}", channel),
+			)],
+		)
+	}
+
+	pub async fn release_held(&mut self, id: &str) {
+		if let Some(client) = self.clients.get_mut(id) {
+			client.release_held().await;
+		}
+	}
+
 	pub async fn read_feed(&mut self, id: &str, buff: Vec<u8>) {
 		if let Some(client) = self.clients.get_mut(id) {
-			client.read_feed(buff).await;
+			// a forged, replayed or malformed feed means the peer is broken or hostile, so we drop it (and its writer)
+			if let Err(e) = client.read_feed(buff).await {
+				eprintln!("invalid feed from client {id}: {e}");
+				self.disconnect_client(id);
+			}
 		} else {
 			#[cfg(debug_assertions)]
 			eprintln!("attempting to read feed to invalid client with id: {id}");
 		}
 	}
 
-	pub fn relay_data_to_recepient(&mut self, id: &String, buff: &[u8]) {
+	pub fn relay_data_to_recepient(&mut self, id: &str, buff: &[u8]) {
 		if let Some(client) = self.clients.get_mut(id) {
-			client.make_and_send(&self.id, buff);
+			client.make_and_send(&self.id, buff, false);
 		} else {
 			#[cfg(debug_assertions)]
 			eprintln!("attempting to relay to invalid client with id: {id}");
 		}
 	}
 
-	pub fn relay_data_to_all(&mut self, buff: &[u8]) {
+	pub fn relay_data_to_all(&mut self, buff: &[u8], droppable: bool) {
 		for (_, client) in self.clients.iter_mut() {
-			client.make_and_send(&self.id, buff);
+			client.make_and_send(&self.id, buff, droppable);
+		}
+		for parked in self.parked.values_mut() {
+			parked.miss(buff);
 		}
 	}
 
-	pub fn relay_data_to_others(&mut self, id: &str, buff: &[u8]) {
+	pub fn relay_data_to_channel(&mut self, channel: &str, buff: &[u8], droppable: bool) {
+		let members = match self.channels.get(channel) {
+			Some(members) => members,
+			None => return,
+		};
+
+		for id in members {
+			if let Some(client) = self.clients.get_mut(id) {
+				client.make_and_send(&self.id, buff, droppable);
+			} else if let Some(parked) = self.parked.get_mut(id) {
+				parked.miss(buff);
+			}
+		}
+	}
+
+	pub fn relay_data_to_others(&mut self, id: &str, buff: &[u8], droppable: bool) {
 		for (_, client) in self.clients.iter_mut() {
 			if client.get_id() != id {
-				client.make_and_send(&self.id, buff);
+				client.make_and_send(&self.id, buff, droppable);
 			}
 		}
+		for parked in self.parked.values_mut() {
+			parked.miss(buff);
+		}
 	}
 
 	pub fn set_id(&mut self, id: String) {
@@ -107,13 +688,387 @@ impl InnerServer {
 		&self.id
 	}
 
+	// every message a user broadcasts ends up in the history, the server's own notices (joins, leaves) don't
+	fn record_history(&mut self, feed: &[Instruction]) {
+		for instr in feed {
+			if let Instruction::ReceiveMessage(author, content) = instr {
+				// nobody can claim "Server" as a username (look at lib/src/protocol.rs -> is_valid_username)
+				if author == "Server" {
+					continue;
+				}
+				if let Err(e) = self.history.append(author, content) {
+					eprintln!("unable to record history: {}", e);
+				}
+			}
+		}
+	}
+
+	pub fn send_history(&mut self, id: &str, before: u64, limit: usize) {
+		let entries = self.history.page(before, limit);
+		self.send_instructions_to(id, vec![Instruction::History { entries }])
+	}
+
 	pub fn send_instructions_to_all(&mut self, feed: Vec<Instruction>) {
+		self.record_history(&feed);
+		let droppable = is_chat(&feed);
 		let data = Encoder::from_feed(feed).writer.dump();
-		self.relay_data_to_all(&data)
+		self.relay_data_to_all(&data, droppable)
 	}
 
 	pub fn send_instructions_to_others(&mut self, sender_id: &str, feed: Vec<Instruction>) {
+		self.record_history(&feed);
+		let droppable = is_chat(&feed);
+		let data = Encoder::from_feed(feed).writer.dump();
+		self.relay_data_to_others(sender_id, &data, droppable)
+	}
+
+	pub fn send_instructions_to(&mut self, id: &str, feed: Vec<Instruction>) {
 		let data = Encoder::from_feed(feed).writer.dump();
-		self.relay_data_to_others(sender_id, &data)
+		self.relay_data_to_recepient(id, &data)
+	}
+
+	fn send_instructions_to_channel_members(&mut self, channel: &str, feed: Vec<Instruction>) {
+		let droppable = is_chat(&feed);
+		let data = Encoder::from_feed(feed).writer.dump();
+		self.relay_data_to_channel(channel, &data, droppable)
+	}
+
+	// only members of a channel may talk in it
+	pub fn send_instructions_to_channel(
+		&mut self,
+		sender_id: &str,
+		channel: &str,
+		feed: Vec<Instruction>,
+	) {
+		if !self.is_in_channel(sender_id, channel) {
+			return self.send_not_in_channel(sender_id, channel);
+		}
+
+		self.send_instructions_to_channel_members(channel, feed)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{client::Client, feed::handle_feed, Event, InnerServer, Sender};
+	use crate::{config::Config, history::MemoryHistory};
+	use futures::StreamExt;
+	use lib::{
+		codec::FrameCodec,
+		encoding::{Decoder, ErrorCode, Instruction},
+		encryption::{Opener, Session},
+		handshake::derive_session_keys,
+	};
+	use std::time::Duration;
+	use tokio::{
+		net::{tcp::OwnedReadHalf, TcpListener, TcpStream},
+		sync::mpsc,
+		time::timeout,
+	};
+	use tokio_util::codec::FramedRead;
+
+	// the client's end of a connection, only ever reads what the server sends it
+	struct Peer {
+		id: String,
+		frames: FramedRead<OwnedReadHalf, FrameCodec>,
+		opener: Opener,
+	}
+
+	impl Peer {
+		// every instruction sent until `done` matches one of them
+		async fn read_until(&mut self, done: impl Fn(&Instruction) -> bool) -> Vec<Instruction> {
+			let mut read = vec![];
+			while !read.iter().any(&done) {
+				let frame = timeout(Duration::from_secs(5), self.frames.next())
+					.await
+					.expect("nothing sent in time")
+					.expect("connection closed")
+					.unwrap();
+				let data = self.opener.open(&frame.data).unwrap();
+				read.extend(Decoder::from_bytes(data).unwrap().feed);
+			}
+			read
+		}
+	}
+
+	fn server() -> (InnerServer, Sender, mpsc::UnboundedReceiver<super::Event>) {
+		let mut server = InnerServer::new(&Config::default(), Box::new(MemoryHistory::default()));
+		server.set_id(server.new_session_id());
+		let (sender, receiver) = mpsc::unbounded_channel();
+		(server, sender, receiver)
+	}
+
+	// a client past the key exchange, the same keys on both ends
+	async fn connect(server: &mut InnerServer, sender: &Sender) -> Peer {
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let stream = TcpStream::connect(listener.local_addr().unwrap())
+			.await
+			.unwrap();
+		let (accepted, _) = listener.accept().await.unwrap();
+
+		let id = server.new_session_id();
+		let server_id = server.get_id().to_string();
+		let keys = || derive_session_keys(b"shared secret", id.as_bytes());
+		let (_, write) = accepted.into_split();
+		let client = Client::new(
+			id.clone(),
+			write,
+			keys(),
+			sender.clone(),
+			server_id.clone(),
+			server.get_limits(),
+		);
+		server.add_client(id.clone(), client);
+
+		let keys = keys();
+		let (_, opener) = Session::new(
+			keys.client_to_server,
+			keys.server_to_client,
+			id.clone(),
+			server_id,
+		)
+		.split();
+		let (read, _) = stream.into_split();
+		Peer {
+			id,
+			frames: FramedRead::new(read, FrameCodec::default()),
+			opener,
+		}
+	}
+
+	// what the broker would do with the events clients sent it, only the ones the tests need
+	fn forward_events(server: &mut InnerServer, receiver: &mut mpsc::UnboundedReceiver<Event>) {
+		while let Ok(event) = receiver.try_recv() {
+			match event {
+				Event::ListUsers(id) => server.send_user_list(&id),
+				Event::SendToOthers(id, feed) => server.send_instructions_to_others(&id, feed),
+				Event::SendToAll(feed) => server.send_instructions_to_all(feed),
+				_ => {}
+			}
+		}
+	}
+
+	fn resume_token(server: &InnerServer, id: &str) -> String {
+		server.clients[id].resume_token().unwrap().to_string()
+	}
+
+	#[tokio::test]
+	async fn test_resume_reclaims_channels_and_missed_messages() {
+		let (mut server, sender, _receiver) = server();
+		let lost = connect(&mut server, &sender).await;
+		server.claim_nick(&lost.id, "frosty".to_string());
+		server.join_channel(&lost.id, "#rust".to_string());
+		let token = resume_token(&server, &lost.id);
+		server.connection_lost(&lost.id);
+
+		let other = connect(&mut server, &sender).await;
+		server.claim_nick(&other.id, "spike".to_string());
+		let missed =
+			|| Instruction::ReceiveMessage("spike".to_string(), "where did you go".to_string());
+		server.send_instructions_to_all(vec![missed()]);
+
+		let mut back = connect(&mut server, &sender).await;
+		server.resume(&back.id, token);
+
+		assert_eq!(server.clients[&back.id].username, "frosty");
+		let members = &server.channels["#rust"];
+		assert!(members.contains(&back.id) && !members.contains(&lost.id));
+		back.read_until(|instr| *instr == missed()).await;
+	}
+
+	#[tokio::test]
+	async fn test_messages_wait_for_the_username_claim() {
+		let (mut server, sender, mut receiver) = server();
+		let peer = connect(&mut server, &sender).await;
+
+		let feed = vec![
+			Instruction::Hello(1, 0),
+			Instruction::Instantiate("frosty".to_string()),
+			Instruction::SendMessage("first!".to_string()),
+		];
+		handle_feed(server.clients.get_mut(&peer.id).unwrap(), feed).await;
+
+		// nothing goes out before the broker got to the claim
+		let username = match receiver.try_recv() {
+			Ok(Event::ClaimNick(_, username)) => username,
+			other => panic!("expected a claim, got {:?}", other),
+		};
+		assert!(receiver.try_recv().is_err());
+
+		server.claim_nick(&peer.id, username);
+		server.release_held(&peer.id).await;
+		let mut sent = vec![];
+		while let Ok(event) = receiver.try_recv() {
+			if let Event::SendToAll(feed) = event {
+				sent.extend(feed);
+			}
+		}
+		assert!(sent.contains(&Instruction::ReceiveMessage(
+			"frosty".to_string(),
+			"first!".to_string()
+		)));
+	}
+
+	#[tokio::test]
+	async fn test_resume_token_is_single_use() {
+		let (mut server, sender, _receiver) = server();
+		let lost = connect(&mut server, &sender).await;
+		server.claim_nick(&lost.id, "frosty".to_string());
+		let token = resume_token(&server, &lost.id);
+		server.connection_lost(&lost.id);
+
+		let back = connect(&mut server, &sender).await;
+		server.resume(&back.id, token.clone());
+		assert_ne!(resume_token(&server, &back.id), token);
+
+		let mut again = connect(&mut server, &sender).await;
+		server.resume(&again.id, token);
+		assert!(!server.clients[&again.id].is_instantiated());
+		again
+			.read_until(|instr| matches!(instr, Instruction::Error(ErrorCode::InvalidResume, _)))
+			.await;
+	}
+
+	#[tokio::test]
+	async fn test_claim_rejects_a_case_variant_of_a_taken_name() {
+		let (mut server, sender, _receiver) = server();
+		let owner = connect(&mut server, &sender).await;
+		server.claim_nick(&owner.id, "frosty".to_string());
+
+		let mut other = connect(&mut server, &sender).await;
+		server.claim_nick(&other.id, "Frosty".to_string());
+		assert!(!server.clients[&other.id].is_instantiated());
+		other
+			.read_until(|instr| matches!(instr, Instruction::Error(ErrorCode::NicknameTaken, _)))
+			.await;
+	}
+
+	#[tokio::test]
+	async fn test_nick_change_is_checked_and_announced() {
+		let (mut server, sender, _receiver) = server();
+		let mut owner = connect(&mut server, &sender).await;
+		server.claim_nick(&owner.id, "frosty".to_string());
+		let mut renamed = connect(&mut server, &sender).await;
+		server.claim_nick(&renamed.id, "spike".to_string());
+
+		server.claim_nick(&renamed.id, "FROSTY".to_string());
+		assert_eq!(server.clients[&renamed.id].username, "spike");
+		renamed
+			.read_until(|instr| matches!(instr, Instruction::Error(ErrorCode::NicknameTaken, _)))
+			.await;
+
+		server.claim_nick(&renamed.id, "spiky".to_string());
+		let changed = Instruction::NickChanged("spike".to_string(), "spiky".to_string());
+		owner.read_until(|instr| *instr == changed).await;
+		renamed.read_until(|instr| *instr == changed).await;
+	}
+
+	#[tokio::test]
+	async fn test_channels_reach_only_members() {
+		let (mut server, sender, _receiver) = server();
+		let mut member = connect(&mut server, &sender).await;
+		server.claim_nick(&member.id, "frosty".to_string());
+		let mut joining = connect(&mut server, &sender).await;
+		server.claim_nick(&joining.id, "spike".to_string());
+		let mut outsider = connect(&mut server, &sender).await;
+		server.claim_nick(&outsider.id, "rarity".to_string());
+
+		server.join_channel(&member.id, "#rust".to_string());
+		server.join_channel(&joining.id, "#rust".to_string());
+		let joined = Instruction::ChannelJoined("#rust".to_string(), "spike".to_string());
+		member.read_until(|instr| *instr == joined).await;
+		joining.read_until(|instr| *instr == joined).await;
+
+		let message = || {
+			Instruction::ChannelMessage("#rust".to_string(), "frosty".to_string(), "hi".to_string())
+		};
+		server.send_instructions_to_channel(&member.id, "#rust", vec![message()]);
+		joining.read_until(|instr| *instr == message()).await;
+
+		server.send_instructions_to_channel(&outsider.id, "#rust", vec![message()]);
+		let mut read = outsider
+			.read_until(|instr| matches!(instr, Instruction::Error(ErrorCode::NotInChannel, _)))
+			.await;
+
+		// everything the outsider got up to this, the channel message wasn't part of it
+		let marker = Instruction::Pong(1);
+		server.send_instructions_to_all(vec![Instruction::Pong(1)]);
+		read.extend(outsider.read_until(|instr| *instr == marker).await);
+		assert!(!read.contains(&message()));
+
+		server.part_channel(&joining.id, "#rust".to_string());
+		let parted = Instruction::ChannelParted("#rust".to_string(), "spike".to_string());
+		member.read_until(|instr| *instr == parted).await;
+		joining.read_until(|instr| *instr == parted).await;
+	}
+
+	#[tokio::test]
+	async fn test_user_list_and_updates() {
+		let (mut server, sender, mut receiver) = server();
+		let mut online = connect(&mut server, &sender).await;
+		server.claim_nick(&online.id, "frosty".to_string());
+		forward_events(&mut server, &mut receiver);
+
+		let mut joining = connect(&mut server, &sender).await;
+		server.claim_nick(&joining.id, "spike".to_string());
+		forward_events(&mut server, &mut receiver);
+
+		let read = joining
+			.read_until(|instr| matches!(instr, Instruction::UserList(_)))
+			.await;
+		assert!(read.iter().any(|instr| matches!(
+			instr,
+			Instruction::UserList(usernames) if usernames.contains(&"frosty".to_string())
+		)));
+		let joined = Instruction::UserJoined("spike".to_string());
+		online.read_until(|instr| *instr == joined).await;
+
+		server.disconnect_client(&joining.id);
+		let left = Instruction::UserLeft("spike".to_string());
+		online.read_until(|instr| *instr == left).await;
+	}
+
+	#[tokio::test]
+	async fn test_history_skips_server_notices() {
+		let (mut server, sender, _receiver) = server();
+		let peer = connect(&mut server, &sender).await;
+		server.claim_nick(&peer.id, "frosty".to_string());
+		server.send_instructions_to_all(vec![Instruction::ReceiveMessage(
+			"frosty".to_string(),
+			"hi".to_string(),
+		)]);
+		server.send_instructions_to_all(vec![Instruction::ReceiveMessage(
+			"Server".to_string(),
+			"frosty left the chat".to_string(),
+		)]);
+
+		let entries = server.history.page(0, 10);
+		assert_eq!(entries.len(), 1);
+		assert_eq!(entries[0].author, "frosty");
+	}
+
+	#[tokio::test]
+	async fn test_key_offer_to_nobody_is_rejected() {
+		let (mut server, sender, _receiver) = server();
+		let mut peer = connect(&mut server, &sender).await;
+		server.claim_nick(&peer.id, "frosty".to_string());
+
+		server.forward_key_offer(&peer.id, "Spike", vec![1, 2, 3]);
+		let rejected = Instruction::DirectKeyRejected {
+			username: "Spike".to_string(),
+		};
+		peer.read_until(|instr| *instr == rejected).await;
+	}
+
+	#[tokio::test]
+	async fn test_relay_to_a_gone_peer_names_it() {
+		let (mut server, sender, _receiver) = server();
+		let mut peer = connect(&mut server, &sender).await;
+		let gone = connect(&mut server, &sender).await;
+		server.connection_lost(&gone.id);
+
+		server.relay_direct(&peer.id, &gone.id, vec![1, 2, 3]);
+		let gone = Instruction::PeerGone { id: gone.id };
+		peer.read_until(|instr| *instr == gone).await;
 	}
 }
@@ -0,0 +1,317 @@
+use super::{Event, Sender};
+use lib::{
+	codec::{encode_frame, Frame, DEFAULT_MAX_FRAME_SIZE},
+	encryption::Sealer,
+};
+use std::{
+	collections::VecDeque,
+	fmt,
+	sync::{Arc, Mutex},
+	time::Duration,
+};
+use tokio::{io::AsyncWriteExt, net::tcp::OwnedWriteHalf, sync::Notify, time::timeout};
+
+// a client which can't take a single frame in this long is treated as gone
+const WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub enum Outgoing {
+	// from the server, sealed by the writer just before it goes out
+	Local {
+		sender: String,
+		data: Vec<u8>,
+		droppable: bool, // only chat broadcasts, missing one of those costs the client a line of chat & nothing else
+	},
+	Opaque {
+		sender: String,
+		buffer: Vec<u8>,
+	}, // end to end encrypted by another client, passed along as is
+}
+
+impl Outgoing {
+	/*
+	only frames which are not sealed yet can be dropped, the sequence number is given out when sealing
+	so the client never notices a gap. an opaque frame already has its sequence number in it,
+	dropping one would break the direct message session for good
+	*/
+	fn can_drop(&self) -> bool {
+		matches!(
+			self,
+			Outgoing::Local {
+				droppable: true,
+				..
+			}
+		)
+	}
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct QueueStats {
+	pub depth: usize,   // frames waiting right now
+	pub peak: usize,    // most frames ever waiting at once
+	pub written: u64,   // frames written to the socket
+	pub dropped: usize, // frames thrown away because the client fell behind, ever
+}
+
+// the client fell too far behind, it should be disconnected
+#[derive(Debug)]
+pub struct SlowConsumer(pub QueueStats);
+
+impl fmt::Display for SlowConsumer {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(
+			f,
+			"client is not keeping up ({} frames waiting, {} dropped)",
+			self.0.depth, self.0.dropped
+		)
+	}
+}
+
+impl std::error::Error for SlowConsumer {}
+
+struct State {
+	frames: VecDeque<Outgoing>,
+	stats: QueueStats,
+	behind: usize, // frames dropped since the queue was last empty
+	closed: bool,  // nothing new comes in, the writer finishes what is left & stops
+}
+
+/*
+Bounded queue between the broker & the writer task of a single client
+
+the broker never waits on a socket, when a client reads slower than we write to it
+the oldest droppable frame makes room for the new one, past max_dropped without the queue
+ever running empty in between the client is let go
+*/
+#[derive(Clone)]
+pub struct WriteQueue {
+	state: Arc<Mutex<State>>,
+	notify: Arc<Notify>,
+	capacity: usize,
+	max_dropped: usize,
+}
+
+impl WriteQueue {
+	pub fn new(capacity: usize, max_dropped: usize) -> Self {
+		Self {
+			state: Arc::new(Mutex::new(State {
+				frames: VecDeque::with_capacity(capacity),
+				stats: QueueStats::default(),
+				behind: 0,
+				closed: false,
+			})),
+			notify: Arc::new(Notify::new()),
+			capacity,
+			max_dropped,
+		}
+	}
+
+	pub fn push(&self, frame: Outgoing) -> Result<(), SlowConsumer> {
+		let mut state = self.state.lock().expect("write queue poisoned");
+		if state.closed {
+			return Ok(());
+		}
+
+		if state.frames.len() >= self.capacity {
+			let oldest = match state.frames.iter().position(Outgoing::can_drop) {
+				Some(oldest) => oldest,
+				None => return Err(SlowConsumer(state.stats)),
+			};
+			state.frames.remove(oldest);
+			state.stats.dropped += 1;
+			state.behind += 1;
+
+			if state.behind > self.max_dropped {
+				return Err(SlowConsumer(state.stats));
+			}
+		}
+
+		state.frames.push_back(frame);
+		state.stats.depth = state.frames.len();
+		state.stats.peak = state.stats.peak.max(state.stats.depth);
+		drop(state);
+
+		self.notify.notify_one();
+		Ok(())
+	}
+
+	// waits for the next frame, None once the queue is closed & empty
+	async fn pop(&self) -> Option<Outgoing> {
+		loop {
+			{
+				let mut state = self.state.lock().expect("write queue poisoned");
+				if let Some(frame) = state.frames.pop_front() {
+					state.stats.depth = state.frames.len();
+					// caught up, whatever was dropped before doesn't count against it anymore
+					if state.frames.is_empty() {
+						state.behind = 0;
+					}
+					return Some(frame);
+				}
+				if state.closed {
+					return None;
+				}
+			}
+
+			// a notify_one from before we got here is kept, so nothing is missed
+			self.notify.notified().await;
+		}
+	}
+
+	fn mark_written(&self) {
+		self.state
+			.lock()
+			.expect("write queue poisoned")
+			.stats
+			.written += 1;
+	}
+
+	pub fn close(&self) {
+		self.state.lock().expect("write queue poisoned").closed = true;
+		self.notify.notify_one();
+	}
+
+	pub fn stats(&self) -> QueueStats {
+		self.state.lock().expect("write queue poisoned").stats
+	}
+}
+
+// owns the write half & the sending half of the session, so only this task ever writes to the client
+pub async fn write_client(
+	id: String,
+	queue: WriteQueue,
+	mut write: OwnedWriteHalf,
+	mut sealer: Sealer,
+	sender: Sender,
+) {
+	while let Some(frame) = queue.pop().await {
+		// size hint (64 bits) | sender (512 bits) | encrypted buffer, look at lib/src/encryption.rs -> Session
+		let frame = match frame {
+			Outgoing::Local { sender, data, .. } => {
+				if !Sealer::fits(data.len(), DEFAULT_MAX_FRAME_SIZE) {
+					eprintln!(
+						"not sending {} bytes to client {id}, it doesn't fit in a frame",
+						data.len()
+					);
+					continue;
+				}
+				Frame::new(sender, sealer.seal(&data))
+			}
+			Outgoing::Opaque { sender, buffer } => Frame::new(sender, buffer),
+		};
+		let payload = match encode_frame(frame) {
+			Ok(payload) => payload,
+			Err(e) => {
+				// the frame may already be sealed, the session can't go on without it
+				eprintln!("unable to make payload for client {id}: {}", e);
+				let _ = sender.send(Event::DisconnectClient(id));
+				return;
+			}
+		};
+
+		match timeout(WRITE_TIMEOUT, write.write_all(&payload)).await {
+			Ok(Ok(())) => queue.mark_written(),
+			Ok(Err(e)) => {
+				#[cfg(debug_assertions)]
+				eprintln!("error while writing to client {id}: {e}");
+
+				let _ = sender.send(Event::ClientDisconnected(id));
+				return;
+			}
+			Err(_) => {
+				#[cfg(debug_assertions)]
+				eprintln!("timed out writing to client {id}");
+
+				let _ = sender.send(Event::ClientDisconnected(id));
+				return;
+			}
+		}
+	}
+
+	let _ = write.shutdown().await;
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn local(data: u8) -> Outgoing {
+		Outgoing::Local {
+			sender: String::new(),
+			data: vec![data],
+			droppable: false,
+		}
+	}
+
+	fn chat(data: u8) -> Outgoing {
+		Outgoing::Local {
+			sender: String::new(),
+			data: vec![data],
+			droppable: true,
+		}
+	}
+
+	fn opaque(data: u8) -> Outgoing {
+		Outgoing::Opaque {
+			sender: String::new(),
+			buffer: vec![data],
+		}
+	}
+
+	fn data(frame: Outgoing) -> u8 {
+		match frame {
+			Outgoing::Local { data, .. } => data[0],
+			Outgoing::Opaque { buffer, .. } => buffer[0],
+		}
+	}
+
+	#[tokio::test]
+	async fn test_drops_oldest_chat_frame() {
+		let queue = WriteQueue::new(4, 10);
+		queue.push(opaque(0)).unwrap();
+		queue.push(local(1)).unwrap();
+		queue.push(chat(2)).unwrap();
+		queue.push(chat(3)).unwrap();
+		queue.push(chat(4)).unwrap();
+
+		let stats = queue.stats();
+		assert_eq!((stats.depth, stats.peak, stats.dropped), (4, 4, 1));
+
+		queue.close();
+		let mut left = Vec::new();
+		while let Some(frame) = queue.pop().await {
+			left.push(data(frame));
+		}
+		// the opaque & the other local frame are older but can't be dropped
+		assert_eq!(left, vec![0, 1, 3, 4]);
+	}
+
+	#[test]
+	fn test_slow_consumer() {
+		let queue = WriteQueue::new(1, 2);
+		queue.push(chat(0)).unwrap();
+		queue.push(chat(1)).unwrap();
+		queue.push(chat(2)).unwrap();
+		assert!(queue.push(chat(3)).is_err());
+
+		// nothing left to drop
+		let queue = WriteQueue::new(1, 2);
+		queue.push(opaque(0)).unwrap();
+		assert!(queue.push(chat(1)).is_err());
+
+		let queue = WriteQueue::new(1, 2);
+		queue.push(local(0)).unwrap();
+		assert!(queue.push(chat(1)).is_err());
+	}
+
+	#[tokio::test]
+	async fn test_drops_are_forgiven_once_drained() {
+		let queue = WriteQueue::new(1, 2);
+		for round in 0..3 {
+			queue.push(chat(0)).unwrap();
+			queue.push(chat(1)).unwrap();
+			queue.push(chat(2)).unwrap();
+			assert!(queue.pop().await.is_some());
+			assert_eq!(queue.stats().dropped, (round + 1) * 2);
+		}
+	}
+}